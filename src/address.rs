@@ -30,7 +30,7 @@ pub struct Address {
 impl Address {
     pub fn from_base(base: u64) -> Self {
         Address {
-            base: base,
+            base,
             pointer: None,
             offset: 0,
         }
@@ -56,7 +56,7 @@ impl Address {
                     0
                 };
                 Address {
-                    base: base,
+                    base,
                     pointer: None,
                     offset: self.offset,
                 }
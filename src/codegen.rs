@@ -0,0 +1,758 @@
+use std::collections::HashMap;
+use std::io;
+use std::io::Write;
+
+use itertools::Itertools;
+use log::warn;
+use serde_json::{json, Map, Value};
+
+use crate::diff::{compare_stores, ChangeKind};
+use crate::pdb_store::{MemberLocation, MemberType, PdbStore};
+use crate::pe::{arch_name, CodeView};
+use crate::utils::version::parse_version;
+
+fn macro_name(struct_name: &str, member: &str) -> String {
+    // _EPROCESS UniqueProcessId -> EPROCESS_UniqueProcessId
+    format!("{}_{}", struct_name.trim_start_matches('_'), member.replace('.', "_"))
+}
+
+pub fn write_c_header<W: Write>(
+    store: &PdbStore,
+    fields: &[(String, String)],
+    title: &str,
+    stream: &mut W,
+) -> io::Result<()> {
+    // #define for every requested member, bitfields also get their bit position and length.
+    // Missing members are left as a comment and reported on stderr.
+    writeln!(stream, "// {}", title)?;
+    writeln!(stream, "// Generated by lpus, do not edit")?;
+    writeln!(stream, "#pragma once")?;
+    writeln!(stream)?;
+
+    for (struct_name, member) in fields {
+        let name = macro_name(struct_name, member);
+        match store.get_field_offset(struct_name, member) {
+            Some(MemberLocation::Byte(offset)) => {
+                writeln!(stream, "#define {} 0x{:x}", name, offset)?;
+            }
+            Some(MemberLocation::Bitfield { byte, bit, len }) => {
+                writeln!(stream, "#define {} 0x{:x}", name, byte)?;
+                writeln!(stream, "#define {}_BIT {}", name, bit)?;
+                writeln!(stream, "#define {}_LEN {}", name, len)?;
+            }
+            None => {
+                warn!("{}.{} not found, skipped in C header", struct_name, member);
+                writeln!(stream, "// {} missing: {}.{} not found", name, struct_name, member)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn const_ident(name: &str) -> String {
+    // _EPROCESS -> EPROCESS, UniqueProcessId -> UNIQUE_PROCESS_ID, Pcb.Header -> PCB_HEADER
+    let chars: Vec<char> = name.trim_start_matches('_').chars().collect();
+    let mut ident = String::new();
+    for (i, &c) in chars.iter().enumerate() {
+        if !c.is_ascii_alphanumeric() {
+            if !ident.ends_with('_') {
+                ident.push('_');
+            }
+            continue;
+        }
+        if c.is_ascii_uppercase() && i > 0 {
+            let prev = chars[i - 1];
+            let next_lower = chars.get(i + 1).is_some_and(|n| n.is_ascii_lowercase());
+            let boundary = prev.is_ascii_lowercase()
+                || prev.is_ascii_digit()
+                || (prev.is_ascii_uppercase() && next_lower);
+            if boundary && !ident.ends_with('_') {
+                ident.push('_');
+            }
+        }
+        ident.push(c.to_ascii_uppercase());
+    }
+    if ident.starts_with(|c: char| c.is_ascii_digit()) {
+        ident.insert(0, '_');
+    }
+    ident
+}
+
+fn module_ident(version: &str) -> String {
+    // 10.0.22621.1 -> v10_0_22621_1
+    format!("v{}", version.replace(|c: char| !c.is_ascii_alphanumeric(), "_"))
+}
+
+pub fn write_rust_consts<W: Write>(
+    versions: &[(String, PdbStore)],
+    fields: &[(String, String)],
+    stream: &mut W,
+) -> io::Result<()> {
+    // One module per version with a const for every requested member,
+    // members missing in a version are left out of its module.
+    writeln!(stream, "// Generated by lpus, do not edit")?;
+    writeln!(stream, "#![allow(dead_code)]")?;
+
+    for (version, store) in versions {
+        writeln!(stream)?;
+        writeln!(stream, "pub mod {} {{", module_ident(version))?;
+        for (struct_name, member) in fields {
+            let name = format!("{}_{}", const_ident(struct_name), const_ident(member));
+            match store.get_field_offset(struct_name, member) {
+                Some(MemberLocation::Byte(offset)) => {
+                    writeln!(stream, "    pub const {}: usize = 0x{:x};", name, offset)?;
+                }
+                Some(MemberLocation::Bitfield { byte, bit, len }) => {
+                    writeln!(stream, "    pub const {}: usize = 0x{:x};", name, byte)?;
+                    writeln!(stream, "    pub const {}_BIT: usize = {};", name, bit)?;
+                    writeln!(stream, "    pub const {}_LEN: usize = {};", name, len)?;
+                }
+                None => writeln!(stream, "    // {} missing: {}.{} not found", name, struct_name, member)?,
+            }
+        }
+        writeln!(stream, "}}")?;
+    }
+    Ok(())
+}
+
+fn timeline_order(versions: &[(String, PdbStore)]) -> Vec<&(String, PdbStore)> {
+    // by build number, 10.0.9 before 10.0.10, with other architectures after x64
+    let mut ordered: Vec<_> = versions.iter().collect();
+    ordered.sort_by_cached_key(|(key, _)| {
+        let (version, arch) = key.split_once('/').unwrap_or((key, ""));
+        (parse_version(version), arch.to_string(), key.clone())
+    });
+    ordered
+}
+
+fn timeline_cell(store: &PdbStore, struct_name: &str, member: &str) -> String {
+    match store.get_field_offset(struct_name, member) {
+        Some(MemberLocation::Byte(offset)) => format!("0x{:x}", offset),
+        Some(MemberLocation::Bitfield { byte, bit, len }) => format!("0x{:x} bit {} len {}", byte, bit, len),
+        None => String::new(),
+    }
+}
+
+// The rows of a timeline, a name and how to get its cell from a version's store
+type TimelineRow<'a> = (String, Box<dyn Fn(&PdbStore) -> String + 'a>);
+
+fn timeline_rows(fields: &[(String, String)]) -> Vec<TimelineRow<'_>> {
+    let mut rows: Vec<TimelineRow> = Vec::new();
+    for (struct_name, member) in fields.iter().filter(|(_, member)| member != "struct_size") {
+        let cell = move |store: &PdbStore| timeline_cell(store, struct_name, member);
+        rows.push((format!("{}.{}", struct_name, member), Box::new(cell)));
+    }
+    for struct_name in fields.iter().map(|(struct_name, _)| struct_name).unique() {
+        let cell = move |store: &PdbStore| {
+            store.struct_size(struct_name).map_or(String::new(), |size| format!("0x{:x}", size))
+        };
+        rows.push((format!("sizeof({})", struct_name), Box::new(cell)));
+    }
+    rows
+}
+
+pub fn write_timeline_csv<W: Write>(
+    versions: &[(String, PdbStore)],
+    updates: &HashMap<String, String>,
+    fields: &[(String, String)],
+    stream: &mut W,
+) -> io::Result<()> {
+    // One row per field and one column per version ordered by build,
+    // an empty cell means the field does not exist in that version.
+    // The sizes of the structs follow as sizeof(<struct>) rows.
+    // The update row names the KB each version first shipped in.
+    let versions = timeline_order(versions);
+    write!(stream, "field")?;
+    for (version, _) in &versions {
+        write!(stream, ",{}", version)?;
+    }
+    writeln!(stream)?;
+    write!(stream, "update")?;
+    for (version, _) in &versions {
+        write!(stream, ",{}", updates.get(version).map_or("", |kb| kb.as_str()))?;
+    }
+    writeln!(stream)?;
+    for (row, cell) in timeline_rows(fields) {
+        write!(stream, "{}", row)?;
+        for (_, store) in &versions {
+            write!(stream, ",{}", cell(store))?;
+        }
+        writeln!(stream)?;
+    }
+    Ok(())
+}
+
+pub fn write_timeline_markdown<W: Write>(
+    versions: &[(String, PdbStore)],
+    updates: &HashMap<String, String>,
+    fields: &[(String, String)],
+    stream: &mut W,
+) -> io::Result<()> {
+    // Same table as the CSV, a cell is bold where the offset differs from the
+    // version before so moves stand out.
+    let versions = timeline_order(versions);
+    write!(stream, "| field |")?;
+    for (version, _) in &versions {
+        write!(stream, " {} |", version)?;
+    }
+    writeln!(stream)?;
+    write!(stream, "|---|")?;
+    for _ in &versions {
+        write!(stream, "---|")?;
+    }
+    writeln!(stream)?;
+    write!(stream, "| update |")?;
+    for (version, _) in &versions {
+        write!(stream, " {} |", updates.get(version).map_or("", |kb| kb.as_str()))?;
+    }
+    writeln!(stream)?;
+    for (row, cell) in timeline_rows(fields) {
+        write!(stream, "| {} |", row)?;
+        let mut previous: Option<String> = None;
+        for (_, store) in &versions {
+            let cell = cell(store);
+            match &previous {
+                Some(previous) if *previous != cell && !cell.is_empty() => write!(stream, " **{}** |", cell)?,
+                _ => write!(stream, " {} |", cell)?,
+            }
+            previous = Some(cell);
+        }
+        writeln!(stream)?;
+    }
+    Ok(())
+}
+
+/// Markdown change log of every release family, the versions sharing a codename
+/// and architecture ordered by build, with what each build changed in the structs
+/// of `fields` compared to the one before. Symbols are left out, their RVAs move
+/// with every build.
+pub fn write_changelog<W: Write>(
+    versions: &[(String, PdbStore)],
+    codenames: &HashMap<String, String>,
+    updates: &HashMap<String, String>,
+    fields: &[(String, String)],
+    stream: &mut W,
+) -> io::Result<()> {
+    let watched: Vec<&String> = fields.iter().map(|(struct_name, _)| struct_name).unique().collect();
+    let family = |key: &String| {
+        let codename = codenames.get(key).map_or("Unknown release", |codename| codename.as_str());
+        let arch = key.split_once('/').map_or("", |(_, arch)| arch);
+        (codename.to_string(), arch.to_string())
+    };
+    let versions = timeline_order(versions);
+    let families = versions.iter().into_group_map_by(|(key, _)| family(key));
+    // families in the order of their first build
+    let families = families.iter().sorted_by_key(|(family, builds)| {
+        let (first, _) = builds[0];
+        (parse_version(first.split('/').next().unwrap_or_default()), *family)
+    });
+    for ((codename, arch), builds) in families {
+        match arch.as_str() {
+            "" => writeln!(stream, "# {}", codename)?,
+            arch => writeln!(stream, "# {} ({})", codename, arch)?,
+        }
+        let mut previous: Option<&PdbStore> = None;
+        for (key, store) in builds {
+            let version = key.split_once('/').map_or(key.as_str(), |(version, _)| version);
+            writeln!(stream)?;
+            match updates.get(key) {
+                Some(kb) => writeln!(stream, "## {} ({})", version, kb)?,
+                None => writeln!(stream, "## {}", version)?,
+            }
+            writeln!(stream)?;
+            let Some(previous) = previous.replace(store) else {
+                writeln!(stream, "First build of the family.")?;
+                continue;
+            };
+            let changes = compare_stores(previous, store).only_changed();
+            let mut lines = Vec::new();
+            let hex = |value: Option<u64>| value.map_or(String::new(), |value| format!("0x{:x}", value));
+            for field in changes.fields.iter().filter(|field| watched.contains(&&field.struct_name)) {
+                let name = format!("{}.{}", field.struct_name, field.member);
+                if field.is_retyped() {
+                    let (old_type, new_type) = (field.old_type.as_deref(), field.new_type.as_deref());
+                    lines.push(format!(
+                        "`{}` at {} changed from {} to {}",
+                        name, hex(field.new), old_type.unwrap_or_default(), new_type.unwrap_or_default()
+                    ));
+                } else {
+                    lines.push(changelog_line(&name, field.kind, &hex(field.old), &hex(field.new)));
+                }
+            }
+            for size in changes.sizes.iter().filter(|size| watched.contains(&&size.struct_name)) {
+                let name = format!("sizeof({})", size.struct_name);
+                lines.push(changelog_line(&name, size.kind, &hex(size.old), &hex(size.new)));
+            }
+            if lines.is_empty() {
+                writeln!(stream, "No changes.")?;
+            }
+            for line in lines {
+                writeln!(stream, "- {}", line)?;
+            }
+        }
+        writeln!(stream)?;
+    }
+    Ok(())
+}
+
+fn changelog_line(name: &str, kind: ChangeKind, old: &str, new: &str) -> String {
+    match kind {
+        ChangeKind::Changed => format!("`{}` moved from {} to {}", name, old, new),
+        ChangeKind::Added => format!("`{}` added at {}", name, new),
+        ChangeKind::Removed => format!("`{}` removed, was at {}", name, old),
+        ChangeKind::Unchanged => format!("`{}` stays at {}", name, new),
+    }
+}
+
+// pdb primitive kind -> ISF base type (name, kind, size, signed)
+const ISF_BASE_TYPES: &[(&str, &str, &str, u64, bool)] = &[
+    ("Void", "void", "void", 0, false),
+    ("Char", "char", "char", 1, true),
+    ("RChar", "char", "char", 1, true),
+    ("I8", "char", "char", 1, true),
+    ("UChar", "unsigned char", "char", 1, false),
+    ("U8", "unsigned char", "char", 1, false),
+    ("WChar", "wchar", "int", 2, false),
+    ("RChar16", "wchar", "int", 2, false),
+    ("RChar32", "unsigned long", "int", 4, false),
+    ("I16", "short", "int", 2, true),
+    ("U16", "unsigned short", "int", 2, false),
+    ("I32", "long", "int", 4, true),
+    ("U32", "unsigned long", "int", 4, false),
+    ("HRESULT", "long", "int", 4, true),
+    ("I64", "long long", "int", 8, true),
+    ("U64", "unsigned long long", "int", 8, false),
+    ("F32", "float", "float", 4, true),
+    ("F64", "double", "float", 8, true),
+    ("Bool8", "bool", "bool", 1, false),
+    ("Bool32", "unsigned long", "int", 4, false),
+];
+
+struct IsfTypes<'a> {
+    store: &'a PdbStore,
+    pointer_size: u64,
+}
+
+impl IsfTypes<'_> {
+    // Turn the member type strings of the store back into ISF types.
+    // Types the store knows nothing about become void.
+    fn isf_type(&self, memtype: &str) -> Value {
+        match MemberType::parse(memtype) {
+            MemberType::Bitfield { base, bit, len } => {
+                json!({"kind": "bitfield", "bit_position": bit, "bit_length": len, "type": self.isf_type(base)})
+            }
+            MemberType::Pointer(pointee) => json!({"kind": "pointer", "subtype": self.isf_type(pointee)}),
+            MemberType::Array { element, bytes } => match self.store.type_size(element, self.pointer_size) {
+                Some(size) if size > 0 => json!({"kind": "array", "count": bytes / size, "subtype": self.isf_type(element)}),
+                _ => json!({"kind": "array", "count": bytes, "subtype": {"kind": "base", "name": "unsigned char"}}),
+            },
+            MemberType::Function(_) => json!({"kind": "function"}),
+            MemberType::Named(name) => {
+                if let Some((_, base, ..)) = ISF_BASE_TYPES.iter().find(|(kind, ..)| *kind == name) {
+                    json!({"kind": "base", "name": base})
+                } else if self.store.structs.contains_key(name) {
+                    json!({"kind": user_type_kind(self.store, name), "name": name})
+                } else if self.store.enums.contains_key(name) {
+                    json!({"kind": "enum", "name": name})
+                } else {
+                    json!({"kind": "base", "name": "void"})
+                }
+            }
+        }
+    }
+}
+
+fn user_type_kind(store: &PdbStore, name: &str) -> &'static str {
+    if store.unions.contains(name) { "union" } else { "struct" }
+}
+
+/// Volatility 3 Intermediate Symbol Format for a version. The PDB identity goes
+/// into the metadata so Volatility can match the file against a memory image.
+pub fn write_isf<W: Write>(
+    store: &PdbStore,
+    codeview: Option<&CodeView>,
+    machine: u16,
+    stream: &mut W,
+) -> io::Result<()> {
+    let pointer_size = if arch_name(machine) == Some("x86") { 4 } else { 8 };
+    let types = IsfTypes { store, pointer_size };
+
+    let mut base_types = Map::new();
+    for (_, name, kind, size, signed) in ISF_BASE_TYPES {
+        base_types.insert(
+            name.to_string(),
+            json!({"kind": kind, "size": size, "signed": signed, "endian": "little"}),
+        );
+    }
+    base_types.insert(
+        "pointer".to_string(),
+        json!({"kind": "int", "size": pointer_size, "signed": false, "endian": "little"}),
+    );
+
+    let mut user_types = Map::new();
+    for (struct_name, member_info) in &store.structs {
+        let mut fields = Map::new();
+        let mut size = 0;
+        for (member, (memtype, offset)) in member_info {
+            if member == "struct_size" {
+                size = *offset;
+                continue;
+            }
+            fields.insert(member.clone(), json!({"offset": offset, "type": types.isf_type(memtype)}));
+        }
+        let kind = user_type_kind(store, struct_name);
+        user_types.insert(struct_name.clone(), json!({"kind": kind, "size": size, "fields": fields}));
+    }
+
+    // an enum takes the size of its underlying type, long when it is not a primitive we know
+    let mut enums = Map::new();
+    for (enum_name, (base, constants)) in &store.enums {
+        let (base, size) = ISF_BASE_TYPES
+            .iter()
+            .find(|(kind, ..)| kind == base)
+            .map_or(("long", 4), |(_, name, _, size, _)| (*name, *size));
+        enums.insert(enum_name.clone(), json!({"size": size, "base": base, "constants": constants}));
+    }
+
+    let symbols: Map<String, Value> = store
+        .symbols
+        .iter()
+        .map(|(name, rva)| (name.clone(), json!({"address": rva})))
+        .collect();
+
+    let mut metadata = json!({
+        "format": "6.2.0",
+        "producer": {"name": "lpus", "version": env!("CARGO_PKG_VERSION")},
+    });
+    if let Some(codeview) = codeview {
+        metadata["windows"] = json!({
+            "pdb": {
+                "GUID": codeview.guid,
+                "age": codeview.age,
+                "database": codeview.pdb_name,
+                "machine_type": machine,
+            }
+        });
+    }
+
+    let isf = json!({
+        "metadata": metadata,
+        "base_types": base_types,
+        "user_types": user_types,
+        "enums": enums,
+        "symbols": symbols,
+    });
+    serde_json::to_writer_pretty(&mut *stream, &isf)?;
+    writeln!(stream)
+}
+
+// Rekall names the base types a little differently from ISF
+fn rekall_base(isf_name: &str) -> &str {
+    match isf_name {
+        "void" => "Void",
+        "wchar" => "unsigned short",
+        "bool" => "unsigned char",
+        name => name,
+    }
+}
+
+struct RekallTypes<'a> {
+    store: &'a PdbStore,
+    pointer_size: u64,
+}
+
+impl RekallTypes<'_> {
+    // A member type as the name and args of a Rekall profile, pointers and
+    // arrays nest them as target and target_args
+    fn rekall_type(&self, memtype: &str) -> (String, Value) {
+        match MemberType::parse(memtype) {
+            MemberType::Bitfield { base, bit, len } => (
+                "BitField".to_string(),
+                json!({"start_bit": bit, "end_bit": bit + len, "target": self.rekall_type(base).0}),
+            ),
+            MemberType::Pointer(pointee) => {
+                let (target, args) = self.rekall_type(pointee);
+                ("Pointer".to_string(), json!({"target": target, "target_args": target_args(args)}))
+            }
+            MemberType::Array { element, bytes } => match self.store.type_size(element, self.pointer_size) {
+                Some(size) if size > 0 => {
+                    let (target, args) = self.rekall_type(element);
+                    let args = json!({"count": bytes / size, "target": target, "target_args": target_args(args)});
+                    ("Array".to_string(), args)
+                }
+                _ => ("Array".to_string(), json!({"count": bytes, "target": "unsigned char", "target_args": null})),
+            },
+            MemberType::Function(_) => ("Function".to_string(), json!({})),
+            MemberType::Named(name) => {
+                if let Some((base, _)) = self.store.enums.get(name) {
+                    let target = self.rekall_type(base).0;
+                    return ("Enumeration".to_string(), json!({"enum_name": name, "target": target}));
+                }
+                let name = if let Some((_, base, ..)) = ISF_BASE_TYPES.iter().find(|(kind, ..)| *kind == name) {
+                    rekall_base(base)
+                } else if self.store.structs.contains_key(name) {
+                    name
+                } else {
+                    "Void"
+                };
+                (name.to_string(), json!({}))
+            }
+        }
+    }
+}
+
+// Rekall writes null rather than {} when the target takes no args
+fn target_args(args: Value) -> Value {
+    match args.as_object() {
+        Some(object) if object.is_empty() => Value::Null,
+        _ => args,
+    }
+}
+
+/// Rekall profile of a version: $STRUCTS with `[size, {member: [offset, [type, args]]}]`
+/// per struct, the symbols as $CONSTANTS and the enums as $ENUMS, value -> name, and
+/// $REVENUMS, name -> value. The store does not tell functions from data, so
+/// $FUNCTIONS is left empty.
+pub fn write_rekall<W: Write>(
+    store: &PdbStore,
+    codeview: Option<&CodeView>,
+    machine: u16,
+    stream: &mut W,
+) -> io::Result<()> {
+    let pointer_size = if arch_name(machine) == Some("x86") { 4 } else { 8 };
+    let types = RekallTypes { store, pointer_size };
+
+    let mut structs = Map::new();
+    for (struct_name, member_info) in &store.structs {
+        let mut fields = Map::new();
+        let mut size = 0;
+        for (member, (memtype, offset)) in member_info {
+            if member == "struct_size" {
+                size = *offset;
+                continue;
+            }
+            let (name, args) = types.rekall_type(memtype);
+            fields.insert(member.clone(), json!([offset, [name, args]]));
+        }
+        structs.insert(struct_name.clone(), json!([size, fields]));
+    }
+
+    let constants: Map<String, Value> = store
+        .symbols
+        .iter()
+        .map(|(name, rva)| (name.clone(), json!(rva)))
+        .collect();
+
+    let mut enums = Map::new();
+    let mut reverse_enums = Map::new();
+    for (enum_name, (_, constants)) in &store.enums {
+        let by_value: Map<String, Value> = constants
+            .iter()
+            .sorted()
+            .map(|(name, value)| (value.to_string(), json!(name)))
+            .collect();
+        enums.insert(enum_name.clone(), by_value.into());
+        reverse_enums.insert(enum_name.clone(), json!(constants));
+    }
+
+    let arch = match arch_name(machine) {
+        Some("x86") => "I386",
+        Some("arm64") => "ARM64",
+        _ => "AMD64",
+    };
+    let mut metadata = json!({
+        "Type": "Profile",
+        "ProfileClass": "BasicPEProfile",
+        "arch": arch,
+    });
+    if let Some(codeview) = codeview {
+        let pdb_name = codeview.pdb_name.to_lowercase();
+        if pdb_name.starts_with("ntkrnl") || pdb_name.starts_with("ntoskrnl") {
+            metadata["ProfileClass"] = "Nt".into();
+        } else if pdb_name.starts_with("win32k") {
+            metadata["ProfileClass"] = "Win32k".into();
+        }
+        metadata["PDBFile"] = codeview.pdb_name.clone().into();
+        metadata["GUID_AGE"] = format!("{}{:X}", codeview.guid, codeview.age).into();
+    }
+
+    let profile = json!({
+        "$METADATA": metadata,
+        "$STRUCTS": structs,
+        "$CONSTANTS": constants,
+        "$FUNCTIONS": {},
+        "$ENUMS": enums,
+        "$REVENUMS": reverse_enums,
+    });
+    serde_json::to_writer_pretty(&mut *stream, &profile)?;
+    writeln!(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+    use std::path::Path;
+
+    use super::*;
+    use crate::pdb_store::read_pdb;
+    use crate::pe::IMAGE_FILE_MACHINE_AMD64;
+
+    const FIXTURE_PDB: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/fixture.pdb");
+
+    fn fixture() -> PdbStore {
+        read_pdb(Path::new(FIXTURE_PDB)).unwrap()
+    }
+
+    fn members(members: &[(&str, &str, u64)]) -> HashMap<String, (String, u64)> {
+        members
+            .iter()
+            .map(|(name, memtype, offset)| (name.to_string(), (memtype.to_string(), *offset)))
+            .collect()
+    }
+
+    fn isf() -> Value {
+        let structs = HashMap::from([
+            (
+                "_EPROCESS".to_string(),
+                members(&[
+                    ("struct_size", "U32", 0x800),
+                    ("Pcb", "_KPROCESS", 0),
+                    ("PoolType", "_POOL_TYPE", 0x58),
+                    ("Token", "_EX_FAST_REF", 0x4b8),
+                ]),
+            ),
+            ("_KPROCESS".to_string(), members(&[("struct_size", "U32", 0x438)])),
+            (
+                "_EX_FAST_REF".to_string(),
+                members(&[("struct_size", "U32", 8), ("Object", "Void*", 0), ("Value", "U64", 0)]),
+            ),
+        ]);
+        let constants = HashMap::from([
+            ("NonPagedPool".to_string(), 0),
+            ("PagedPool".to_string(), 1),
+            ("NonPagedPoolNx".to_string(), 512),
+        ]);
+        let store = PdbStore {
+            symbols: HashMap::new(),
+            structs,
+            unions: HashSet::from(["_EX_FAST_REF".to_string()]),
+            enums: HashMap::from([("_POOL_TYPE".to_string(), ("I32".to_string(), constants))]),
+        };
+        let mut out = Vec::new();
+        write_isf(&store, None, IMAGE_FILE_MACHINE_AMD64, &mut out).unwrap();
+        serde_json::from_slice(&out).unwrap()
+    }
+
+    #[test]
+    fn isf_tells_unions_from_structs() {
+        let isf = isf();
+        assert_eq!(isf["user_types"]["_EPROCESS"]["kind"], "struct");
+        assert_eq!(isf["user_types"]["_EX_FAST_REF"]["kind"], "union");
+        assert_eq!(isf["user_types"]["_EX_FAST_REF"]["size"], 8);
+        let token = &isf["user_types"]["_EPROCESS"]["fields"]["Token"];
+        assert_eq!(token["type"], json!({"kind": "union", "name": "_EX_FAST_REF"}));
+        let pcb = &isf["user_types"]["_EPROCESS"]["fields"]["Pcb"];
+        assert_eq!(pcb["type"], json!({"kind": "struct", "name": "_KPROCESS"}));
+    }
+
+    #[test]
+    fn isf_has_the_enums() {
+        let isf = isf();
+        assert_eq!(
+            isf["enums"]["_POOL_TYPE"],
+            json!({
+                "size": 4,
+                "base": "long",
+                "constants": {"NonPagedPool": 0, "PagedPool": 1, "NonPagedPoolNx": 512},
+            })
+        );
+        let pool_type = &isf["user_types"]["_EPROCESS"]["fields"]["PoolType"];
+        assert_eq!(pool_type, &json!({"offset": 0x58, "type": {"kind": "enum", "name": "_POOL_TYPE"}}));
+    }
+
+    fn rekall() -> Value {
+        let codeview = CodeView {
+            pdb_name: "ntkrnlmp.pdb".to_string(),
+            guid: "3844DBB920174967BE7AA4A2C20430FA".to_string(),
+            age: 1,
+        };
+        let mut out = Vec::new();
+        write_rekall(&fixture(), Some(&codeview), IMAGE_FILE_MACHINE_AMD64, &mut out).unwrap();
+        serde_json::from_slice(&out).unwrap()
+    }
+
+    // [type, args] of a member, args being null or an object, and the same for the
+    // target of pointers and arrays
+    fn check_rekall_type(name: &str, args: &Value) {
+        assert!(args.is_object() || args.is_null(), "{}: {}", name, args);
+        if let Some(target) = args.get("target") {
+            let target = target.as_str().unwrap_or_else(|| panic!("{}: target {}", name, target));
+            if let Some(target_args) = args.get("target_args") {
+                check_rekall_type(target, target_args);
+            }
+        }
+    }
+
+    #[test]
+    fn rekall_profile_has_the_expected_layout() {
+        let profile = rekall();
+        let sections = profile.as_object().unwrap().keys().cloned().sorted().collect::<Vec<_>>();
+        assert_eq!(sections, ["$CONSTANTS", "$ENUMS", "$FUNCTIONS", "$METADATA", "$REVENUMS", "$STRUCTS"]);
+        assert_eq!(
+            profile["$METADATA"],
+            json!({
+                "Type": "Profile",
+                "ProfileClass": "Nt",
+                "arch": "AMD64",
+                "PDBFile": "ntkrnlmp.pdb",
+                "GUID_AGE": "3844DBB920174967BE7AA4A2C20430FA1",
+            })
+        );
+
+        // every struct is [size, {member: [offset, [type, args]]}]
+        for (struct_name, definition) in profile["$STRUCTS"].as_object().unwrap() {
+            let [size, members] = definition.as_array().unwrap().as_slice() else {
+                panic!("{}: {}", struct_name, definition);
+            };
+            assert!(size.is_u64(), "{}", struct_name);
+            for (member, layout) in members.as_object().unwrap() {
+                let [offset, member_type] = layout.as_array().unwrap().as_slice() else {
+                    panic!("{}.{}: {}", struct_name, member, layout);
+                };
+                assert!(offset.is_u64(), "{}.{}", struct_name, member);
+                let [type_name, args] = member_type.as_array().unwrap().as_slice() else {
+                    panic!("{}.{}: {}", struct_name, member, member_type);
+                };
+                check_rekall_type(type_name.as_str().unwrap(), args);
+            }
+        }
+        assert_eq!(profile["$CONSTANTS"]["PsLoadedModuleList"], 0x3080);
+        assert_eq!(profile["$FUNCTIONS"], json!({}));
+    }
+
+    #[test]
+    fn rekall_profile_has_the_members_of_a_known_struct() {
+        let profile = rekall();
+        let eprocess = &profile["$STRUCTS"]["_EPROCESS"];
+        assert_eq!(eprocess[0], 0x70);
+        let members = &eprocess[1];
+        assert_eq!(members["Pcb"], json!([0x0, ["_KPROCESS", {}]]));
+        assert_eq!(members["Token"], json!([0x50, ["_EX_FAST_REF", {}]]));
+        assert_eq!(members["UniqueProcessId"], json!([0x30, ["Pointer", {"target": "Void", "target_args": null}]]));
+        assert_eq!(
+            members["ProcessExiting"],
+            json!([0x48, ["BitField", {"start_bit": 2, "end_bit": 3, "target": "unsigned long"}]])
+        );
+        assert_eq!(
+            members["ImageFileName"],
+            json!([0x5c, ["Array", {"count": 15, "target": "unsigned char", "target_args": null}]])
+        );
+        assert_eq!(
+            members["PoolType"],
+            json!([0x58, ["Enumeration", {"enum_name": "_POOL_TYPE", "target": "long"}]])
+        );
+        let pool_types = json!({"0": "NonPagedPool", "1": "PagedPool", "512": "NonPagedPoolNx"});
+        assert_eq!(profile["$ENUMS"]["_POOL_TYPE"], pool_types);
+        assert_eq!(profile["$REVENUMS"]["_POOL_TYPE"]["NonPagedPoolNx"], 512);
+    }
+}
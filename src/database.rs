@@ -0,0 +1,146 @@
+use std::path::Path;
+use std::time::Duration;
+
+use rusqlite::{params, Connection};
+
+use crate::pdb_store::PdbStore;
+use crate::pe::CodeView;
+
+// Every PE of a run shares the database, its rows are told apart by pe_name
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS versions (
+    pe_name TEXT NOT NULL,
+    version TEXT NOT NULL,
+    codename TEXT NOT NULL,
+    timestamp INTEGER NOT NULL,
+    pdb_name TEXT,
+    pdb_guid TEXT,
+    pdb_age INTEGER,
+    PRIMARY KEY (pe_name, version)
+);
+CREATE TABLE IF NOT EXISTS offsets (
+    pe_name TEXT NOT NULL,
+    version TEXT NOT NULL,
+    struct_name TEXT NOT NULL,
+    field_name TEXT NOT NULL,
+    offset INTEGER NOT NULL,
+    PRIMARY KEY (pe_name, version, struct_name, field_name)
+);
+CREATE TABLE IF NOT EXISTS symbols (
+    pe_name TEXT NOT NULL,
+    version TEXT NOT NULL,
+    name TEXT NOT NULL,
+    rva INTEGER NOT NULL,
+    PRIMARY KEY (pe_name, version, name)
+);
+";
+
+// Created once the tables are migrated, the old ones have no pe_name
+const INDEXES: &str = "
+CREATE INDEX IF NOT EXISTS offsets_field ON offsets (pe_name, struct_name, field_name);
+";
+
+// Databases created before pe_name was recorded keyed the rows by version alone,
+// sqlite cannot change a primary key so the tables are rebuilt. Their rows get
+// an empty pe_name.
+const ADD_PE_NAME: &str = "
+ALTER TABLE versions RENAME TO versions_old;
+ALTER TABLE offsets RENAME TO offsets_old;
+ALTER TABLE symbols RENAME TO symbols_old;
+";
+const COPY_WITHOUT_PE_NAME: &str = "
+INSERT INTO versions SELECT '', version, codename, timestamp, pdb_name, pdb_guid, pdb_age FROM versions_old;
+INSERT INTO offsets SELECT '', version, struct_name, field_name, offset FROM offsets_old;
+INSERT INTO symbols SELECT '', version, name, rva FROM symbols_old;
+DROP TABLE versions_old;
+DROP TABLE offsets_old;
+DROP TABLE symbols_old;
+";
+
+pub struct Database {
+    conn: Connection,
+}
+
+impl Database {
+    pub fn open(path: &Path) -> rusqlite::Result<Self> {
+        let mut conn = Connection::open(path)?;
+        // another run writing the same database holds the lock for one version
+        conn.busy_timeout(Duration::from_secs(30))?;
+        conn.execute_batch(SCHEMA)?;
+        // databases created before the pdb identity was recorded lack its columns
+        let has_pdb = conn
+            .prepare("SELECT 1 FROM pragma_table_info('versions') WHERE name = 'pdb_guid'")?
+            .exists([])?;
+        if !has_pdb {
+            conn.execute_batch(
+                "ALTER TABLE versions ADD COLUMN pdb_name TEXT;
+                 ALTER TABLE versions ADD COLUMN pdb_guid TEXT;
+                 ALTER TABLE versions ADD COLUMN pdb_age INTEGER;",
+            )?;
+        }
+        let has_pe_name = conn
+            .prepare("SELECT 1 FROM pragma_table_info('versions') WHERE name = 'pe_name'")?
+            .exists([])?;
+        if !has_pe_name {
+            let tx = conn.transaction()?;
+            tx.execute_batch(ADD_PE_NAME)?;
+            tx.execute_batch(SCHEMA)?;
+            tx.execute_batch(COPY_WITHOUT_PE_NAME)?;
+            tx.commit()?;
+        }
+        conn.execute_batch(INDEXES)?;
+        Ok(Database { conn })
+    }
+
+    /// Store the offsets of one version of a PE, version is its version_key.
+    pub fn insert_version(
+        &mut self,
+        pe_name: &str,
+        version: &str,
+        codename: &str,
+        timestamp: u32,
+        codeview: Option<&CodeView>,
+        store: &PdbStore,
+    ) -> rusqlite::Result<()> {
+        // One transaction per version, re-running a version replaces its rows
+        let tx = self.conn.transaction()?;
+        tx.execute(
+            "INSERT INTO versions (pe_name, version, codename, timestamp, pdb_name, pdb_guid, pdb_age)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+             ON CONFLICT(pe_name, version) DO UPDATE SET codename = excluded.codename, timestamp = excluded.timestamp,
+                pdb_name = excluded.pdb_name, pdb_guid = excluded.pdb_guid, pdb_age = excluded.pdb_age",
+            params![
+                pe_name,
+                version,
+                codename,
+                timestamp,
+                codeview.map(|codeview| codeview.pdb_name.as_str()),
+                codeview.map(|codeview| codeview.formatted_guid()),
+                codeview.map(|codeview| codeview.age),
+            ],
+        )?;
+        // fields and symbols no longer in the store must not linger from an earlier run
+        tx.execute("DELETE FROM offsets WHERE pe_name = ?1 AND version = ?2", params![pe_name, version])?;
+        tx.execute("DELETE FROM symbols WHERE pe_name = ?1 AND version = ?2", params![pe_name, version])?;
+        {
+            let mut insert_offset = tx.prepare(
+                "INSERT INTO offsets (pe_name, version, struct_name, field_name, offset) VALUES (?1, ?2, ?3, ?4, ?5)",
+            )?;
+            for (struct_name, member_info) in &store.structs {
+                for (member, (_memtype, offset)) in member_info {
+                    if member != "struct_size" {
+                        insert_offset.execute(params![pe_name, version, struct_name, member, *offset as i64])?;
+                    }
+                }
+            }
+
+            let mut insert_symbol = tx.prepare(
+                "INSERT INTO symbols (pe_name, version, name, rva) VALUES (?1, ?2, ?3, ?4)",
+            )?;
+            for (name, rva) in &store.symbols {
+                insert_symbol.execute(params![pe_name, version, name, *rva as i64])?;
+            }
+        }
+        tx.commit()
+    }
+}
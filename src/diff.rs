@@ -0,0 +1,225 @@
+use std::collections::HashMap;
+
+use itertools::Itertools;
+use serde::Serialize;
+
+use crate::pdb_store::PdbStore;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeKind {
+    Added,
+    Removed,
+    Changed,
+    Unchanged,
+}
+
+impl ChangeKind {
+    fn classify(old: Option<u64>, new: Option<u64>) -> Option<Self> {
+        match (old, new) {
+            (Some(old), Some(new)) if old != new => Some(ChangeKind::Changed),
+            (Some(_), Some(_)) => Some(ChangeKind::Unchanged),
+            (Some(_), None) => Some(ChangeKind::Removed),
+            (None, Some(_)) => Some(ChangeKind::Added),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FieldChange {
+    pub struct_name: String,
+    pub member: String,
+    pub kind: ChangeKind,
+    pub old: Option<u64>,
+    pub new: Option<u64>,
+    // a member can change at the same offset, e.g. a bitfield moving to other bits
+    pub old_type: Option<String>,
+    pub new_type: Option<String>,
+}
+
+impl FieldChange {
+    /// Whether the member kept its offset but changed its type, bit position or length.
+    pub fn is_retyped(&self) -> bool {
+        self.kind == ChangeKind::Changed && self.old == self.new
+    }
+}
+
+// offsets.json written before member types were recorded have an empty type,
+// only their offsets can be compared
+fn type_changed(old: &str, new: &str) -> bool {
+    !old.is_empty() && !new.is_empty() && old != new
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SymbolChange {
+    pub name: String,
+    pub kind: ChangeKind,
+    pub old: Option<u64>,
+    pub new: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SizeChange {
+    pub struct_name: String,
+    pub kind: ChangeKind,
+    pub old: Option<u64>,
+    pub new: Option<u64>,
+}
+
+// Every member and symbol of two stores with how it changed, sorted by name
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct OffsetDiff {
+    pub fields: Vec<FieldChange>,
+    pub symbols: Vec<SymbolChange>,
+    // a struct can grow while the members we watch stay where they are
+    pub sizes: Vec<SizeChange>,
+}
+
+impl OffsetDiff {
+    /// Whether nothing was added, removed or moved.
+    pub fn is_empty(&self) -> bool {
+        self.fields.iter().all(|field| field.kind == ChangeKind::Unchanged)
+            && self.symbols.iter().all(|symbol| symbol.kind == ChangeKind::Unchanged)
+            && self.sizes.iter().all(|size| size.kind == ChangeKind::Unchanged)
+    }
+
+    /// Drop the members and symbols that kept their offset.
+    pub fn only_changed(mut self) -> Self {
+        self.fields.retain(|field| field.kind != ChangeKind::Unchanged);
+        self.symbols.retain(|symbol| symbol.kind != ChangeKind::Unchanged);
+        self.sizes.retain(|size| size.kind != ChangeKind::Unchanged);
+        self
+    }
+}
+
+/// Compare the struct members, struct sizes and symbols of two stores, `a` being the old one.
+/// Members in both at the same offset and of the same type are kept as Unchanged,
+/// see only_changed. Renamed members show up as removed + added.
+pub fn compare_stores(a: &PdbStore, b: &PdbStore) -> OffsetDiff {
+    let mut fields = Vec::new();
+    let mut sizes = Vec::new();
+    let empty = HashMap::new();
+    for struct_name in a.structs.keys().chain(b.structs.keys()).sorted().dedup() {
+        let (old, new) = (a.struct_size(struct_name), b.struct_size(struct_name));
+        if let Some(kind) = ChangeKind::classify(old, new) {
+            sizes.push(SizeChange { struct_name: struct_name.clone(), kind, old, new });
+        }
+
+        let old_members = a.structs.get(struct_name).unwrap_or(&empty);
+        let new_members = b.structs.get(struct_name).unwrap_or(&empty);
+        let members = old_members.keys().chain(new_members.keys()).filter(|member| *member != "struct_size");
+        for member in members.sorted().dedup() {
+            let (old_type, old) = old_members.get(member).map(|(memtype, offset)| (memtype, *offset)).unzip();
+            let (new_type, new) = new_members.get(member).map(|(memtype, offset)| (memtype, *offset)).unzip();
+            let kind = match ChangeKind::classify(old, new) {
+                Some(ChangeKind::Unchanged) if old_type.zip(new_type).is_some_and(|(a, b)| type_changed(a, b)) => {
+                    ChangeKind::Changed
+                }
+                Some(kind) => kind,
+                None => continue,
+            };
+            fields.push(FieldChange {
+                struct_name: struct_name.clone(),
+                member: member.clone(),
+                kind,
+                old,
+                new,
+                old_type: old_type.cloned(),
+                new_type: new_type.cloned(),
+            });
+        }
+    }
+
+    let mut symbols = Vec::new();
+    for name in a.symbols.keys().chain(b.symbols.keys()).sorted().dedup() {
+        let old = a.symbols.get(name).copied();
+        let new = b.symbols.get(name).copied();
+        if let Some(kind) = ChangeKind::classify(old, new) {
+            symbols.push(SymbolChange { name: name.clone(), kind, old, new });
+        }
+    }
+
+    OffsetDiff { fields, symbols, sizes }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    fn store(members: &[(&str, &str, u64)]) -> PdbStore {
+        let mut eprocess: HashMap<String, (String, u64)> = members
+            .iter()
+            .map(|(member, memtype, offset)| (member.to_string(), (memtype.to_string(), *offset)))
+            .collect();
+        eprocess.insert("struct_size".to_string(), ("U32".to_string(), 0xa40));
+        PdbStore {
+            symbols: HashMap::new(),
+            structs: HashMap::from([("_EPROCESS".to_string(), eprocess)]),
+            unions: HashSet::new(),
+            enums: HashMap::new(),
+        }
+    }
+
+    fn kinds(diff: &OffsetDiff) -> Vec<(&str, ChangeKind)> {
+        diff.fields.iter().map(|field| (field.member.as_str(), field.kind)).collect()
+    }
+
+    #[test]
+    fn classifies_added_removed_and_moved_members() {
+        let old = store(&[
+            ("UniqueProcessId", "void*", 0x440),
+            ("ActiveProcessLinks", "_LIST_ENTRY", 0x448),
+            ("Token", "_EX_FAST_REF", 0x4b8),
+        ]);
+        let new = store(&[
+            ("UniqueProcessId", "void*", 0x440),
+            ("ActiveProcessLinks", "_LIST_ENTRY", 0x448),
+            ("Token", "_EX_FAST_REF", 0x4c0),
+            ("MitigationFlags3", "U32", 0x9d8),
+        ]);
+        let diff = compare_stores(&old, &new);
+        assert_eq!(
+            kinds(&diff),
+            [
+                ("ActiveProcessLinks", ChangeKind::Unchanged),
+                ("MitigationFlags3", ChangeKind::Added),
+                ("Token", ChangeKind::Changed),
+                ("UniqueProcessId", ChangeKind::Unchanged),
+            ]
+        );
+        let token = &diff.fields[2];
+        assert_eq!((token.old, token.new), (Some(0x4b8), Some(0x4c0)));
+        assert!(!token.is_retyped());
+
+        let diff = compare_stores(&new, &old).only_changed();
+        assert_eq!(kinds(&diff), [("MitigationFlags3", ChangeKind::Removed), ("Token", ChangeKind::Changed)]);
+        assert!(compare_stores(&old, &old).is_empty());
+    }
+
+    #[test]
+    fn bitfields_moving_within_their_unit_are_changed() {
+        let old = store(&[("ProcessExiting", "U32:2:1", 0x464), ("Wow64Process", "U32:5:1", 0x464)]);
+        let new = store(&[("ProcessExiting", "U32:3:1", 0x464), ("Wow64Process", "U32:5:1", 0x464)]);
+        let diff = compare_stores(&old, &new);
+        assert_eq!(
+            kinds(&diff),
+            [("ProcessExiting", ChangeKind::Changed), ("Wow64Process", ChangeKind::Unchanged)]
+        );
+        let exiting = &diff.fields[0];
+        assert!(exiting.is_retyped());
+        assert_eq!(exiting.old_type.as_deref(), Some("U32:2:1"));
+        assert_eq!(exiting.new_type.as_deref(), Some("U32:3:1"));
+        assert!(!diff.is_empty());
+    }
+
+    #[test]
+    fn members_without_a_type_compare_by_offset() {
+        // an offsets.json from before member types were recorded
+        let old = store(&[("Token", "", 0x4b8)]);
+        let new = store(&[("Token", "_EX_FAST_REF", 0x4b8)]);
+        assert!(compare_stores(&old, &new).is_empty());
+    }
+}
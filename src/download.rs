@@ -0,0 +1,825 @@
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+use std::time::{Duration, UNIX_EPOCH};
+
+use log::{debug, info, warn};
+use reqwest::blocking::Client;
+use reqwest::header::HeaderMap;
+use sha2::{Digest, Sha256};
+use crate::error::{Error, Result};
+use crate::format::SCHEMA_VERSION;
+use crate::pdb_store::{local_pdb, parse_pdb, pdb_path, read_pdb, PdbStore, PDB_SERVER_PATH};
+use crate::pe::{arch_name, PeFile};
+use crate::utils::make_file_id;
+use crate::utils::rate_limit::RateLimiter;
+use crate::winbindex::RecordData;
+
+// Settings shared by every record of a run
+pub struct ProcessOptions<'a> {
+    pub client: &'a Client,
+    // symbol servers tried in order until one has the file
+    pub symbol_servers: &'a [String],
+    pub pe_name: &'a str,
+    pub output_dir: &'a Path,
+    pub force: bool,
+    pub retries: u32,
+    pub verify: bool,
+    pub checksum: bool,
+    pub validate_exports: bool,
+    pub parsed: &'a ParsedStores,
+    // outputs go to <version>/<pe_name>/ instead of <version>/, for runs over several dumps
+    pub pe_dir: bool,
+    // never touch the network, only PEs and pdbs already in output_dir are used
+    pub offline: bool,
+}
+
+// A file downloaded under one hash, the other hashes of it copy or reuse it, then
+// parse it once
+#[derive(Default)]
+struct Slot {
+    // where the PE was downloaded to and its architecture
+    downloaded: Mutex<Option<(PathBuf, &'static str)>>,
+    parsed: Mutex<Option<(Arc<PdbStore>, PdbSource)>>,
+}
+
+/// Where the store of a PE came from, recorded in its offsets.json.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PdbSource {
+    // parsed.json of an earlier run
+    ParsedCache,
+    // the pdb was already next to the PE
+    LocalPdb,
+    SymbolServer,
+}
+
+impl PdbSource {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            PdbSource::ParsedCache => "parsed_cache",
+            PdbSource::LocalPdb => "local_pdb",
+            PdbSource::SymbolServer => "symbol_server",
+        }
+    }
+}
+
+/// Stores parsed during a run keyed by fileId. winbindex lists some binaries under
+/// several hashes, they are downloaded and parsed only once.
+#[derive(Default)]
+pub struct ParsedStores(Mutex<HashMap<String, Arc<Slot>>>);
+
+impl ParsedStores {
+    fn slot(&self, file_id: &str) -> Arc<Slot> {
+        let mut slots = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        slots.entry(file_id.to_string()).or_default().clone()
+    }
+}
+
+/// Bytes received from symbol servers during this run, PEs and PDBs alike.
+pub static DOWNLOADED_BYTES: AtomicU64 = AtomicU64::new(0);
+
+/// The fileId of a PE on the symbol server is its TimeDateStamp followed by its
+/// SizeOfImage, which winbindex records as virtualSize.
+pub fn generate_download_url(server: &str, timestamp: u32, size: u64, pe_name: &str) -> String {
+    // 1. Generate the fileId part
+    let file_id = make_file_id(timestamp, size);
+
+    // 2. Construct the final URL
+    format!(
+        "{}/{}/{}/{}",
+        server.trim_end_matches('/'),
+        pe_name,
+        file_id,
+        pe_name
+    )
+}
+
+/// Symbol servers named in an _NT_SYMBOL_PATH style value, e.g.
+/// srv*C:\symbols*https://symbols.corp/ or SRV*https://a;SRV*https://b.
+/// Only http(s) servers are kept, local caches and directories are ignored.
+pub fn symbol_servers_from_path(symbol_path: &str) -> Vec<String> {
+    symbol_path
+        .split(';')
+        .flat_map(|element| element.split('*'))
+        .filter(|part| part.starts_with("http://") || part.starts_with("https://"))
+        .map(|part| part.to_string())
+        .collect()
+}
+
+/// The servers to download from: the ones given on the command line, else the
+/// ones in _NT_SYMBOL_PATH, else Microsoft's public symbol server.
+pub fn symbol_servers(servers: &[String]) -> Vec<String> {
+    if !servers.is_empty() {
+        return servers.to_vec();
+    }
+    let from_env = std::env::var("_NT_SYMBOL_PATH")
+        .map(|symbol_path| symbol_servers_from_path(&symbol_path))
+        .unwrap_or_default();
+    if !from_env.is_empty() {
+        return from_env;
+    }
+    vec![PDB_SERVER_PATH.to_string()]
+}
+
+/// The HTTP client shared by every download of a run, so connections to the
+/// symbol server are pooled. `timeout` covers a whole request including the body.
+/// Requests go through `proxy` when given, otherwise through the proxy named by
+/// HTTPS_PROXY, HTTP_PROXY or ALL_PROXY (NO_PROXY is honored).
+pub fn http_client(connect_timeout: Duration, timeout: Duration, proxy: Option<&str>) -> reqwest::Result<Client> {
+    let mut builder = Client::builder()
+        .user_agent(concat!("lpus/", env!("CARGO_PKG_VERSION")))
+        .connect_timeout(connect_timeout)
+        .timeout(timeout);
+    match proxy {
+        Some(proxy) => {
+            info!("Using proxy: {}", proxy);
+            builder = builder.proxy(reqwest::Proxy::all(proxy)?);
+        }
+        None => {
+            // reqwest reads these itself, they are only looked at to say so
+            for name in ["HTTPS_PROXY", "https_proxy", "HTTP_PROXY", "http_proxy", "ALL_PROXY", "all_proxy"] {
+                if let Ok(value) = std::env::var(name)
+                    && !value.is_empty()
+                {
+                    info!("Using proxy from {}: {}", name, value);
+                    break;
+                }
+            }
+        }
+    }
+    builder.build()
+}
+
+/// Server errors, timeouts and dropped connections are worth another try,
+/// anything else (e.g. 404) is not going to change.
+fn is_transient(result: &reqwest::Result<reqwest::blocking::Response>) -> bool {
+    match result {
+        Ok(response) => {
+            response.status().is_server_error() || response.status() == reqwest::StatusCode::TOO_MANY_REQUESTS
+        }
+        Err(e) => e.is_timeout() || e.is_connect() || e.is_request(),
+    }
+}
+
+/// Every request to a symbol server goes through this limiter, --rate-limit sets its rate.
+pub static REQUEST_LIMITER: RateLimiter = RateLimiter::new();
+
+/// Bytes to leave free on the volume downloads are written to, --min-free-space
+/// sets it. 0 turns the check off.
+pub static MIN_FREE_SPACE: AtomicU64 = AtomicU64::new(0);
+
+/// Fail with StorageFull when writing len bytes into dir would leave less than
+/// MIN_FREE_SPACE free, before anything is written. dir may not exist yet.
+pub fn check_free_space(dir: &Path, len: u64) -> io::Result<()> {
+    let min_free = MIN_FREE_SPACE.load(Ordering::Relaxed);
+    if min_free == 0 {
+        return Ok(());
+    }
+    let existing = dir.ancestors().find(|dir| dir.is_dir()).unwrap_or(Path::new("."));
+    let available = fs2::available_space(existing)?;
+    if available < min_free.saturating_add(len) {
+        const MIB: f64 = 1024.0 * 1024.0;
+        return Err(io::Error::new(
+            io::ErrorKind::StorageFull,
+            format!(
+                "{:.1} MiB free on the volume of {}, writing {:.1} MiB more would leave less than {:.1} MiB",
+                available as f64 / MIB, dir.display(), len as f64 / MIB, min_free as f64 / MIB
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Write data to path through path.part, renamed into place once complete.
+/// The part is removed when writing fails, e.g. on a full disk.
+pub fn write_atomically(path: &Path, data: &[u8]) -> io::Result<()> {
+    let part = part_path(path);
+    let written = fs::File::create(&part).and_then(|mut file| {
+        file.write_all(data)?;
+        file.sync_all()
+    });
+    if let Err(e) = written {
+        let _ = fs::remove_file(&part);
+        return Err(e);
+    }
+    fs::rename(&part, path)
+}
+
+// Seconds the server asked us to wait in Retry-After
+fn retry_after(response: &reqwest::blocking::Response) -> Option<Duration> {
+    let seconds = response.headers().get(reqwest::header::RETRY_AFTER)?.to_str().ok()?;
+    seconds.trim().parse().ok().map(Duration::from_secs)
+}
+
+/// GET a URL and return the body of the final response, after any redirects.
+/// Transient failures are retried up to `retries` times with exponential backoff.
+/// Every attempt waits for the rate limiter, a 429 or 503 pauses all workers
+/// rather than only the one that got it.
+pub(crate) fn fetch_bytes(client: &Client, url: &str, retries: u32) -> Result<Vec<u8>> {
+    let response = send_with_retries(client, url, retries, HeaderMap::new())?;
+    response_bytes(url, response)
+}
+
+/// The retried GET of fetch_bytes, with extra request headers, e.g. If-None-Match.
+/// Any response that is not worth retrying is returned, whatever its status.
+pub(crate) fn send_with_retries(
+    client: &Client,
+    url: &str,
+    retries: u32,
+    headers: HeaderMap,
+) -> Result<reqwest::blocking::Response> {
+    debug!("Downloading from: {}", url);
+
+    // Make a blocking GET request
+    let mut attempt = 0;
+    loop {
+        REQUEST_LIMITER.acquire();
+        let result = client.get(url).headers(headers.clone()).send();
+        if attempt >= retries || !is_transient(&result) {
+            return Ok(result?);
+        }
+
+        attempt += 1;
+        let mut delay = Duration::from_secs(1 << (attempt - 1));
+        match result {
+            Ok(response) => {
+                let status = response.status();
+                // the server is throttling us
+                if matches!(status, reqwest::StatusCode::TOO_MANY_REQUESTS | reqwest::StatusCode::SERVICE_UNAVAILABLE) {
+                    delay = delay.max(retry_after(&response).unwrap_or_default());
+                    REQUEST_LIMITER.pause(delay);
+                }
+                warn!("Got status {}, retrying in {:?} ({}/{})", status, delay, attempt, retries)
+            }
+            Err(e) => warn!("Request failed: {}, retrying in {:?} ({}/{})", e, delay, attempt, retries),
+        }
+        thread::sleep(delay);
+    }
+}
+
+/// The body of a response to a GET of url, checked to be the whole file.
+pub(crate) fn response_bytes(url: &str, response: reqwest::blocking::Response) -> Result<Vec<u8>> {
+    // msdl redirects to blob storage, every check below is about the final response
+    let final_url = response.url().to_string();
+    if final_url != url {
+        debug!("Redirected to: {}", final_url);
+    }
+
+    if response.status() == reqwest::StatusCode::NOT_FOUND {
+        return Err(Error::NotIndexed(url.to_string()));
+    }
+    if !response.status().is_success() {
+        return Err(Error::Download(format!(
+            "Failed to download file from {}. Status: {}",
+            final_url,
+            response.status()
+        )));
+    }
+    let content_type = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+    if content_type.starts_with("text/html") {
+        return Err(Error::Download(format!("{} returned an HTML page instead of the file", final_url)));
+    }
+
+    let expected_len = response.content_length();
+    let data = response.bytes()?.to_vec();
+    DOWNLOADED_BYTES.fetch_add(data.len() as u64, Ordering::Relaxed);
+    if let Some(expected_len) = expected_len
+        && data.len() as u64 != expected_len
+    {
+        return Err(Error::Download(format!("{} sent {} bytes, expected {}", final_url, data.len(), expected_len)));
+    }
+    Ok(data)
+}
+
+/// A symstore file.ptr names where a file really is, "PATH:<location>",
+/// or why it is not there, "MSG:<text>".
+fn is_file_ptr(data: &[u8]) -> bool {
+    data.len() < 4096 && (data.starts_with(b"PATH:") || data.starts_with(b"MSG:"))
+}
+
+fn follow_file_ptr(client: &Client, data: &[u8], retries: u32) -> Result<Vec<u8>> {
+    let content = String::from_utf8_lossy(data);
+    let content = content.trim();
+    if let Some(message) = content.strip_prefix("MSG:") {
+        return Err(Error::NotIndexed(format!("file.ptr says {}", message)));
+    }
+    let location = content
+        .strip_prefix("PATH:")
+        .ok_or_else(|| Error::Download("Malformed file.ptr".into()))?;
+    info!("Following file.ptr to: {}", location);
+    if location.starts_with("http://") || location.starts_with("https://") {
+        fetch_bytes(client, location, retries)
+    } else {
+        // a share or local path, only reachable when it is mounted here
+        fs::read(location).map_err(|e| Error::Download(format!("Cannot read {} from file.ptr: {}", location, e)))
+    }
+}
+
+/// Where download_file writes a file before moving it into place, e.g. ntoskrnl.exe.part
+fn part_path(target_path: &Path) -> PathBuf {
+    let mut part = target_path.as_os_str().to_owned();
+    part.push(".part");
+    PathBuf::from(part)
+}
+
+/// Remove the .part files left under dir by downloads that were interrupted.
+/// Returns how many were removed.
+pub fn remove_partial_downloads(dir: &Path) -> io::Result<usize> {
+    let mut removed = 0;
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+    for entry in entries {
+        let path = entry?.path();
+        if path.is_dir() {
+            // versions of other architectures are one level deeper, <version>/<arch>
+            removed += remove_partial_downloads(&path)?;
+        } else if path.extension().is_some_and(|ext| ext == "part") {
+            debug!("Removing partial download: {}", path.display());
+            fs::remove_file(&path)?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+/// fetch_bytes for a file on a symbol server. A file.ptr served instead of the
+/// file, or next to a missing one, is followed.
+pub(crate) fn fetch_file(client: &Client, url: &str, retries: u32) -> Result<Vec<u8>> {
+    match fetch_bytes(client, url, retries) {
+        // symstore keeps a file.ptr in place of files stored elsewhere
+        Err(e @ Error::NotIndexed(_)) => {
+            let ptr_url = format!("{}/file.ptr", url.rsplit_once('/').map_or(url, |(dir, _)| dir));
+            match fetch_bytes(client, &ptr_url, retries) {
+                Ok(ptr) if is_file_ptr(&ptr) => follow_file_ptr(client, &ptr, retries),
+                _ => Err(e),
+            }
+        }
+        Ok(data) if is_file_ptr(&data) => follow_file_ptr(client, &data, retries),
+        result => result,
+    }
+}
+
+/// Downloads a file from a URL and saves it to a specified path.
+/// Transient failures are retried up to `retries` times with exponential backoff.
+/// A file.ptr served instead of the file, or next to a missing one, is followed.
+/// The file is written to <target>.part and only renamed to the target once it is
+/// complete and `check` accepts it, so an interrupted download is never taken for
+/// a cached file.
+pub fn download_file<F>(client: &Client, url: &str, target_path: &Path, retries: u32, check: F) -> Result<()>
+where
+    F: Fn(&Path) -> Result<()>,
+{
+    let data = fetch_file(client, url, retries)?;
+
+    // Ensure the parent directory exists, create_dir_all tolerates other workers
+    // creating the same directory concurrently
+    if let Some(parent) = target_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    // Save the file
+    let part = part_path(target_path);
+    check_free_space(target_path.parent().unwrap_or(Path::new(".")), data.len() as u64)?;
+    let written = fs::File::create(&part).and_then(|mut file| {
+        file.write_all(&data)?;
+        file.sync_all()
+    });
+    if let Err(e) = written {
+        let _ = fs::remove_file(&part);
+        return Err(e.into());
+    }
+    if let Err(e) = check(&part) {
+        let _ = fs::remove_file(&part);
+        return Err(e);
+    }
+    fs::rename(&part, target_path)?;
+
+    info!("Successfully downloaded and saved to: {}", target_path.display());
+
+    Ok(())
+}
+
+/// A previously downloaded file is reused when its length matches the record.
+/// file_info.size is the size of the PE on disk, virtualSize is SizeOfImage.
+pub fn is_cached(target_path: &Path, size: u64) -> bool {
+    match fs::metadata(target_path) {
+        Ok(metadata) => metadata.is_file() && metadata.len() == size,
+        Err(_) => false,
+    }
+}
+
+/// The symbol server answers some requests with an HTML error page and a 200,
+/// make sure what we got is a PE of the expected size before parsing it.
+pub fn verify_pe(target_path: &Path, expected_size: u64) -> Result<()> {
+    let mut magic = [0u8; 2];
+    let mut file = File::open(target_path)?;
+    let size = file.metadata()?.len();
+    if size != expected_size {
+        return Err(Error::Download(format!(
+            "{} is {} bytes, expected {} bytes",
+            target_path.display(),
+            size,
+            expected_size
+        )));
+    }
+
+    file.read_exact(&mut magic)?;
+    if &magic != b"MZ" {
+        return Err(Error::Download(format!("{} is not a PE file, no MZ signature", target_path.display())));
+    }
+    Ok(())
+}
+
+/// Architecture of a record from the machineType winbindex stores for it.
+/// Older dumps without one are x64, which is all this tool used to handle.
+pub fn record_arch(record: &RecordData) -> Result<&'static str> {
+    match record.file_info.extra.get("machineType").and_then(|machine| machine.as_u64()) {
+        Some(machine) => u16::try_from(machine)
+            .ok()
+            .and_then(arch_name)
+            .ok_or_else(|| Error::Parse(format!("Unsupported machine type 0x{:x}", machine))),
+        None => Ok("x64"),
+    }
+}
+
+/// Key of a version in the outputs, also its directory under the output directory.
+/// x64 keeps the plain version, other architectures get their own subtree
+/// since their offsets differ, e.g. 10.0.26100.6725/arm64.
+pub fn version_key(version: &str, arch: &str) -> String {
+    match arch {
+        "x64" => version.to_string(),
+        _ => format!("{}/{}", version, arch),
+    }
+}
+
+/// Key of a record's outputs, version_key followed by the PE name when the run
+/// keeps a directory per PE, e.g. 10.0.22621.1/win32k.sys.
+pub fn output_key(version: &str, arch: &str, pe_dir: Option<&str>) -> String {
+    match pe_dir {
+        Some(pe_name) => format!("{}/{}", version_key(version, arch), pe_name),
+        None => version_key(version, arch),
+    }
+}
+
+/// Where the PE of a record is downloaded to: <output_dir>/<output_key>/<pe_name>
+pub fn target_path(record: &RecordData, options: &ProcessOptions) -> Result<PathBuf> {
+    let version = record
+        .file_version()
+        .ok_or_else(|| Error::Parse(format!("Malformed version {:?}", record.file_info.version)))?;
+    let arch = record_arch(record)?;
+    let key = output_key(&version.to_string(), arch, options.pe_dir.then_some(options.pe_name));
+    Ok(options.output_dir.join(key).join(options.pe_name))
+}
+
+/// winbindex keys every record by the SHA256 of the PE, a mismatch means the
+/// download was truncated, corrupted or tampered with.
+pub fn verify_sha256(target_path: &Path, expected: &str) -> Result<()> {
+    let digest = Sha256::digest(fs::read(target_path)?);
+    let actual: String = digest.iter().map(|b| format!("{:02x}", b)).collect();
+    if !actual.eq_ignore_ascii_case(expected) {
+        return Err(Error::Download(format!(
+            "{} has SHA256 {}, expected {}",
+            target_path.display(),
+            actual,
+            expected
+        )));
+    }
+    Ok(())
+}
+
+/// Compare the export RVAs of a PE with the symbol RVAs of its PDB, a mismatch
+/// means the PDB does not belong to this PE. Returns the number of mismatches.
+pub fn validate_exports(pe_path: &Path, store: &PdbStore) -> Result<usize> {
+    let exports = PeFile::open(pe_path)
+        .and_then(|pe| pe.exports())
+        .map_err(|e| Error::Parse(format!("Cannot read the exports of {}: {}", pe_path.display(), e)))?;
+    let mut checked = 0;
+    let mut mismatches = 0;
+    for (name, export_rva) in &exports {
+        let Some(&symbol_rva) = store.symbols.get(name) else {
+            continue;
+        };
+        checked += 1;
+        if symbol_rva != *export_rva as u64 {
+            mismatches += 1;
+            warn!("Export {} is at 0x{:x} in the PE but 0x{:x} in the PDB", name, export_rva, symbol_rva);
+        }
+    }
+    info!("Checked {} of {} exports against the PDB, {} mismatched", checked, exports.len(), mismatches);
+    Ok(mismatches)
+}
+
+// parsed.json next to a PE, the store is only valid for the PE it was parsed from
+#[derive(serde::Serialize, serde::Deserialize)]
+struct ParsedCache<S> {
+    // 0 for caches written before the schema was versioned, their layout is that of schema 1
+    #[serde(default)]
+    schema_version: u64,
+    pe_size: u64,
+    pe_modified: u64,
+    store: S,
+}
+
+fn pe_identity(pe_path: &Path) -> io::Result<(u64, u64)> {
+    let metadata = fs::metadata(pe_path)?;
+    let modified = metadata
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+    Ok((metadata.len(), modified))
+}
+
+/// The store parsed for this PE on an earlier run, if the PE did not change since.
+pub fn load_parsed(pe_path: &Path) -> Option<PdbStore> {
+    let cache_path = pe_path.with_file_name("parsed.json");
+    let cache: ParsedCache<PdbStore> = serde_json::from_reader(io::BufReader::new(File::open(&cache_path).ok()?)).ok()?;
+    if cache.schema_version != SCHEMA_VERSION && cache.schema_version != 0 {
+        debug!("{} is stale, it has schema version {}", cache_path.display(), cache.schema_version);
+        return None;
+    }
+    let (pe_size, pe_modified) = pe_identity(pe_path).ok()?;
+    if cache.pe_size != pe_size || cache.pe_modified != pe_modified {
+        debug!("{} is stale, the PE changed", cache_path.display());
+        return None;
+    }
+    Some(cache.store)
+}
+
+pub fn save_parsed(pe_path: &Path, store: &PdbStore) -> Result<()> {
+    let (pe_size, pe_modified) = pe_identity(pe_path)?;
+    let cache = ParsedCache { schema_version: SCHEMA_VERSION, pe_size, pe_modified, store };
+    let file = io::BufWriter::new(File::create(pe_path.with_file_name("parsed.json"))?);
+    serde_json::to_writer(file, &cache).map_err(io::Error::from)?;
+    Ok(())
+}
+
+/// A PE downloaded for a record, ready to have its PDB parsed.
+pub struct Downloaded {
+    pub path: PathBuf,
+    pub arch: &'static str,
+    file_id: String,
+}
+
+/// Download the PE of a winbindex record into the output directory and parse its PDB.
+/// Returns the store with the architecture of the downloaded PE. A record of a
+/// file already parsed in this run, same fileId under another hash, reuses its store.
+pub fn fetch_and_parse(sha256_hash: &str, record: &RecordData, options: &ProcessOptions) -> Result<(Arc<PdbStore>, &'static str)> {
+    let downloaded = download_record(sha256_hash, record, options)?;
+    let (pdb_store, _) = parse_record(&downloaded, options)?;
+    Ok((pdb_store, downloaded.arch))
+}
+
+/// Download the PE of a winbindex record into the output directory, the first half
+/// of fetch_and_parse. A file already downloaded under another hash is copied.
+pub fn download_record(sha256_hash: &str, record: &RecordData, options: &ProcessOptions) -> Result<Downloaded> {
+    let file_id = make_file_id(record.file_info.timestamp, record.file_info.virtual_size);
+    let slot = options.parsed.slot(&file_id);
+    // a record of a file that is being downloaded by another worker waits here for it
+    let mut downloaded = slot.downloaded.lock().unwrap_or_else(PoisonError::into_inner);
+    let target_path = target_path(record, options)?;
+    if let Some((pe_path, arch)) = downloaded.as_ref() {
+        info!("{} is the same file as {}, reusing it", sha256_hash, pe_path.display());
+        // the output of this record goes next to its own copy of the PE
+        if target_path != *pe_path && !is_cached(&target_path, record.file_info.size) {
+            if let Some(dir) = target_path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            fs::copy(pe_path, &target_path)?;
+        }
+        return Ok(Downloaded{
+            path: target_path,
+            arch,
+            file_id,
+        });
+    }
+
+    let arch = download(sha256_hash, record, options)?;
+    *downloaded = Some((target_path.clone(), arch));
+    Ok(Downloaded{
+        path: target_path,
+        arch,
+        file_id,
+    })
+}
+
+/// Remove the PE of a record with its pdb and parsed cache, once its outputs are
+/// written. A later record of the same file downloads it again.
+pub fn remove_downloaded(record: &RecordData, options: &ProcessOptions) -> Result<()> {
+    let slot = options.parsed.slot(&make_file_id(record.file_info.timestamp, record.file_info.virtual_size));
+    let mut downloaded = slot.downloaded.lock().unwrap_or_else(PoisonError::into_inner);
+    let target_path = target_path(record, options)?;
+    let remove = |path: &Path| match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    };
+    // the pdb is named by the PE, so it goes first
+    if let Ok(codeview) = PeFile::open(&target_path).and_then(|pe| pe.codeview()) {
+        remove(&target_path.with_file_name(codeview.pdb_name))?;
+    }
+    remove(&target_path.with_file_name("parsed.json"))?;
+    remove(&target_path)?;
+    if downloaded.as_ref().is_some_and(|(pe_path, _)| *pe_path == target_path) {
+        *downloaded = None;
+    }
+    debug!("Deleted {} and its pdb", target_path.display());
+    Ok(())
+}
+
+/// Parse the PDB of a downloaded PE, the second half of fetch_and_parse. The store
+/// is shared by every record of the same file.
+/// The parsed cache is used first, then a pdb already next to the PE, then the
+/// symbol servers. When none has it the error is NotIndexed.
+pub fn parse_record(downloaded: &Downloaded, options: &ProcessOptions) -> Result<(Arc<PdbStore>, PdbSource)> {
+    let slot = options.parsed.slot(&downloaded.file_id);
+    let mut parsed = slot.parsed.lock().unwrap_or_else(PoisonError::into_inner);
+    if let Some((pdb_store, source)) = parsed.as_ref() {
+        info!("Reusing the parsed pdb for {}", downloaded.path.display());
+        return Ok((pdb_store.clone(), *source));
+    }
+
+    let target_path = &downloaded.path;
+    let cached = if options.force { None } else { load_parsed(target_path) };
+    let (pdb_store, source) = match cached {
+        Some(pdb_store) => {
+            info!("Using parsed cache for: {}", target_path.display());
+            (pdb_store, PdbSource::ParsedCache)
+        }
+        None => {
+            let local = if options.force { None } else { local_pdb(target_path) };
+            let (pdb_store, source) = match local {
+                Some(local) => {
+                    info!("Using the pdb next to the PE: {}", local.display());
+                    (read_pdb(&local)?, PdbSource::LocalPdb)
+                }
+                None if options.offline => return Err(not_cached(&pdb_path(target_path)?)),
+                None => {
+                    let pdb_store = parse_pdb(options.client, options.symbol_servers, target_path, options.retries)?;
+                    (pdb_store, PdbSource::SymbolServer)
+                }
+            };
+            if let Err(e) = save_parsed(target_path, &pdb_store) {
+                warn!("Failed to cache the parsed pdb for {}: {}", target_path.display(), e);
+            }
+            (pdb_store, source)
+        }
+    };
+    if options.validate_exports {
+        validate_exports(target_path, &pdb_store)?;
+    }
+    let pdb_store = Arc::new(pdb_store);
+    *parsed = Some((pdb_store.clone(), source));
+    Ok((pdb_store, source))
+}
+
+fn not_cached(path: &Path) -> Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("{} is not cached and --offline is set", path.display())).into()
+}
+
+fn download(sha256_hash: &str, record: &RecordData, options: &ProcessOptions) -> Result<&'static str> {
+    let pe_name = options.pe_name;
+    info!("Processing record for SHA256: {}", sha256_hash);
+
+    // --- 4. Extract required fields ---
+    let timestamp = record.file_info.timestamp;
+    // The symbol server wants SizeOfImage (virtualSize), the size on disk is
+    // only tried when that 404s
+    let size = record.file_info.virtual_size;
+    let alternate_size = record.file_info.size;
+
+    // Extract the core version number (e.g., "10.0.10240.17914")
+    let version = record
+        .file_version()
+        .ok_or_else(|| Error::Parse(format!("Malformed version {:?}", record.file_info.version)))?;
+
+    debug!("Timestamp: {}", timestamp);
+    debug!("Image Size: {}", size);
+    debug!("Version: {}", version);
+    debug!("PE Name (used for URL): {}", pe_name);
+
+    // --- 5. Generate the target path ---
+    // The downloaded file keeps the PE name, e.g. ntoskrnl.exe
+    let arch = record_arch(record)?;
+    let target_path = target_path(record, options)?;
+    debug!("Target Path: {}", target_path.display());
+
+    // A download is checked before it is moved into place, a cached file when it is reused
+    let check = |path: &Path| -> Result<()> {
+        if options.verify {
+            verify_pe(path, record.file_info.size)?;
+        }
+        if options.checksum {
+            verify_sha256(path, sha256_hash)?;
+        }
+        Ok(())
+    };
+
+    if !options.force && is_cached(&target_path, record.file_info.size) {
+        info!("Using cached file: {}", target_path.display());
+        check(&target_path)?;
+    } else if options.offline {
+        return Err(not_cached(&target_path));
+    } else {
+        // no point fetching what cannot be saved
+        check_free_space(target_path.parent().unwrap_or(Path::new(".")), record.file_info.size)?;
+        // Servers are tried in order, the first one that has the PE wins
+        let mut last_error = Error::Download("No symbol server to download from".into());
+        let mut downloaded = false;
+        for server in options.symbol_servers {
+            let download_url = generate_download_url(server, timestamp, size, pe_name);
+            debug!("Download URL: {}", download_url);
+            let result = match download_file(options.client, &download_url, &target_path, options.retries, check) {
+                Err(Error::NotIndexed(_)) if alternate_size != size => {
+                    warn!("Not found with size 0x{:x}, retrying with size 0x{:x}", size, alternate_size);
+                    let alternate_url = generate_download_url(server, timestamp, alternate_size, pe_name);
+                    download_file(options.client, &alternate_url, &target_path, options.retries, check)
+                        .inspect(|_| info!("Downloaded with alternate size 0x{:x} (file size)", alternate_size))
+                }
+                result => result.inspect(|_| info!("Downloaded with size 0x{:x} (SizeOfImage)", size)),
+            };
+            match result {
+                Ok(()) => {
+                    downloaded = true;
+                    break;
+                }
+                Err(e) => {
+                    warn!("Failed to download from {}: {}", server, e);
+                    last_error = e;
+                }
+            }
+        }
+        if !downloaded {
+            return Err(last_error);
+        }
+    }
+
+    // The PE header is what the offsets are really for, not what the dump claims
+    let pe = PeFile::open(&target_path).map_err(|e| Error::Parse(format!("Cannot read {}: {}", target_path.display(), e)))?;
+    let machine = pe.machine;
+    let pe_arch = arch_name(machine).ok_or_else(|| Error::Parse(format!("Unsupported machine type 0x{:x}", machine)))?;
+    if pe_arch != arch {
+        return Err(Error::Parse(format!(
+            "{} is {}, winbindex lists it as {}",
+            target_path.display(),
+            pe_arch,
+            arch
+        )));
+    }
+    debug!("Architecture: {}", arch);
+    // stale winbindex metadata would put the offsets under the wrong version
+    match pe.file_version() {
+        Ok(pe_version) if pe_version != version => warn!(
+            "{} has version {} in its resources, winbindex lists it as {}",
+            target_path.display(),
+            pe_version,
+            version
+        ),
+        Ok(_) => {}
+        Err(e) => debug!("Cannot read the version resource of {}: {}", target_path.display(), e),
+    }
+    Ok(arch)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn download_urls_name_the_file_by_timestamp_and_image_size() {
+        // the record of test.json, TimeDateStamp 602770787 and SizeOfImage 21299200
+        assert_eq!(
+            generate_download_url(PDB_SERVER_PATH, 602770787, 21299200, "ntoskrnl.exe"),
+            "https://msdl.microsoft.com/download/symbols/ntoskrnl.exe/23ED8D631450000/ntoskrnl.exe"
+        );
+    }
+
+    #[test]
+    fn download_urls_pad_the_timestamp_and_keep_the_size_lowercase() {
+        assert_eq!(
+            generate_download_url("https://symbols.example/", 0x1a2b, 0x9a5000, "win32k.sys"),
+            "https://symbols.example/win32k.sys/00001A2B9a5000/win32k.sys"
+        );
+        assert_eq!(
+            generate_download_url("https://symbols.example", 0, 0xabc, "ci.dll"),
+            "https://symbols.example/ci.dll/00000000abc/ci.dll"
+        );
+        assert_eq!(
+            generate_download_url("https://symbols.example", 0x1a2b, 0, "ci.dll"),
+            "https://symbols.example/ci.dll/00001A2B0/ci.dll"
+        );
+    }
+}
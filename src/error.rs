@@ -0,0 +1,45 @@
+use std::io;
+
+/// Why fetching or parsing a PE and its PDB failed. A Download error may succeed
+/// on another try or server, NotIndexed and Parse will not.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// The symbol server has no file under this URL, a 404. Some winbindex records
+    /// were never indexed, this is expected rather than a failure.
+    #[error("Not indexed on the symbol server: {0}")]
+    NotIndexed(String),
+    // the request failed, or what came back is not the whole file
+    #[error("{0}")]
+    Download(String),
+    // the PE, PDB or winbindex record cannot be read or does not add up
+    #[error("{0}")]
+    Parse(String),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    /// Short name of the variant, for structured logs.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::NotIndexed(_) => "not_indexed",
+            Error::Download(_) => "download",
+            Error::Parse(_) => "parse",
+            Error::Io(_) => "io",
+        }
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Self {
+        Error::Download(e.to_string())
+    }
+}
+
+impl From<pdb::Error> for Error {
+    fn from(e: pdb::Error) -> Self {
+        Error::Parse(e.to_string())
+    }
+}
@@ -0,0 +1,163 @@
+//! The layout of the JSON files a run writes: offsets.json of every version and
+//! the all-offsets.json master file holding them all. Both carry "schema_version",
+//! bump SCHEMA_VERSION whenever their structure changes and teach the migrate
+//! functions to bring older files up to date.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::download::PdbSource;
+use crate::winbindex::Update;
+
+/// The schema of the files this build writes, and the newest it can read.
+pub const SCHEMA_VERSION: u64 = 1;
+
+/// A member of a struct in offsets.json.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Member {
+    pub offset: u64,
+    // the member type as kept in the store, e.g. _EX_PUSH_LOCK, Void*, U32:0:1
+    #[serde(rename = "type")]
+    pub type_name: String,
+    pub size: Option<u64>,
+    pub pointer: bool,
+}
+
+/// What PdbStore::to_json writes: the symbols, the structs and their sizes.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Offsets {
+    pub symbols: BTreeMap<String, u64>,
+    pub structs: BTreeMap<String, BTreeMap<String, Member>>,
+    pub sizes: BTreeMap<String, u64>,
+}
+
+/// What a symbol server needs to hand out the pdb of a version again.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PdbIdentity {
+    pub name: String,
+    // 8-4-4-4-12 uppercase hex, as CodeView::formatted_guid
+    pub guid: String,
+    pub age: u32,
+}
+
+/// offsets.json of one version, and its entry in the master file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VersionOffsets {
+    pub schema_version: u64,
+    pub codename: String,
+    pub version: String,
+    pub arch: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pdb: Option<PdbIdentity>,
+    // how the run got the pdb, absent in files written before it was recorded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pdb_source: Option<PdbSource>,
+    // the KBs that shipped this build, oldest first
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub updates: Vec<Update>,
+    // function -> byte signature with ?? wildcards, with --signatures
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub signatures: BTreeMap<String, String>,
+    #[serde(flatten)]
+    pub offsets: Offsets,
+}
+
+/// all-offsets.json, the offsets of every version keyed like the output directories.
+/// V is the map of versions, e.g. BTreeMap<String, VersionOffsets>.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MasterFile<V> {
+    pub schema_version: u64,
+    pub versions: V,
+}
+
+// Files written before the schema was versioned have no schema_version
+fn schema_version(value: &serde_json::Value) -> Result<u64, String> {
+    match value.get("schema_version") {
+        None => Ok(0),
+        Some(version) => match version.as_u64() {
+            Some(version) if version <= SCHEMA_VERSION => Ok(version),
+            Some(version) => Err(format!(
+                "schema version {} is newer than {}, the newest this build reads",
+                version, SCHEMA_VERSION
+            )),
+            None => Err(format!("bad schema version {}", version)),
+        },
+    }
+}
+
+/// Bring an offsets.json, or an entry of the master file, to SCHEMA_VERSION.
+/// A file of a newer schema is rejected.
+pub fn migrate_offsets(mut value: serde_json::Value) -> Result<serde_json::Value, String> {
+    // 0 -> 1: only schema_version is new, PdbStore::from_json still reads the
+    // bare offsets and missing sizes of the oldest files
+    schema_version(&value)?;
+    let offsets = value.as_object_mut().ok_or("offsets are not a JSON object")?;
+    offsets.insert("schema_version".into(), SCHEMA_VERSION.into());
+    Ok(value)
+}
+
+/// Bring a master file to SCHEMA_VERSION and return its versions.
+/// A file of a newer schema is rejected.
+pub fn migrate_master(mut value: serde_json::Value) -> Result<serde_json::Map<String, serde_json::Value>, String> {
+    let versions = match schema_version(&value)? {
+        // 0 -> 1: the versions were the whole file, they moved under "versions"
+        0 => value,
+        _ => value.get_mut("versions").map(serde_json::Value::take).ok_or("no versions in the master file")?,
+    };
+    let serde_json::Value::Object(versions) = versions else {
+        return Err("the versions are not a JSON object".into());
+    };
+    versions
+        .into_iter()
+        .map(|(key, offsets)| Ok((key.clone(), migrate_offsets(offsets).map_err(|e| format!("{}: {}", key, e))?)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn migrates_offsets_to_the_current_schema() {
+        // the oldest offsets.json, bare offsets and no schema_version
+        let old = json!({"symbols": {"PsLoadedModuleList": 0xcfc430}, "structs": {"_EPROCESS": {"Token": 0x4b8}}});
+        let migrated = migrate_offsets(old.clone()).unwrap();
+        assert_eq!(migrated["schema_version"], SCHEMA_VERSION);
+        assert_eq!(migrated["structs"], old["structs"]);
+        assert_eq!(migrate_offsets(migrated.clone()).unwrap(), migrated);
+    }
+
+    #[test]
+    fn rejects_offsets_it_cannot_read() {
+        let newer = migrate_offsets(json!({"schema_version": SCHEMA_VERSION + 1})).unwrap_err();
+        assert!(newer.contains("newer"), "{}", newer);
+        assert!(migrate_offsets(json!({"schema_version": "1"})).is_err());
+        assert!(migrate_offsets(json!([])).is_err());
+    }
+
+    #[test]
+    fn migrates_master_files_to_the_current_schema() {
+        let offsets = json!({"symbols": {}, "structs": {}});
+        // schema 0, the versions were the whole file
+        let old = json!({"10.0.19041.1/x64": offsets, "10.0.22000.1/x64": offsets});
+        let versions = migrate_master(old).unwrap();
+        assert_eq!(versions.keys().collect::<Vec<_>>(), ["10.0.19041.1/x64", "10.0.22000.1/x64"]);
+        assert!(versions.values().all(|offsets| offsets["schema_version"] == SCHEMA_VERSION));
+
+        let current = json!({"schema_version": SCHEMA_VERSION, "versions": {"10.0.26100.1/x64": offsets}});
+        let versions = migrate_master(current).unwrap();
+        assert_eq!(versions.keys().collect::<Vec<_>>(), ["10.0.26100.1/x64"]);
+    }
+
+    #[test]
+    fn rejects_master_files_it_cannot_read() {
+        assert!(migrate_master(json!({"schema_version": SCHEMA_VERSION})).is_err());
+        assert!(migrate_master(json!({"schema_version": SCHEMA_VERSION + 1, "versions": {}})).is_err());
+        // a bad entry is named in the error
+        let bad_entry = json!({"schema_version": SCHEMA_VERSION, "versions": {"10.0.26100.1/x64": []}});
+        assert!(migrate_master(bad_entry).unwrap_err().starts_with("10.0.26100.1/x64: "));
+    }
+}
@@ -0,0 +1,12 @@
+pub mod address;
+pub mod codegen;
+pub mod database;
+pub mod diff;
+pub mod download;
+pub mod error;
+pub mod format;
+pub mod pdb_store;
+pub mod pe;
+pub mod signature;
+pub mod utils;
+pub mod winbindex;
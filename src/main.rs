@@ -1,258 +1,1983 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::fs;
 use std::fs::File;
-use std::io::Write;
-use std::path::Path;
+use std::io;
+use std::io::{IsTerminal, Write};
+use std::num::NonZeroUsize;
+use std::ops::{ControlFlow, RangeInclusive};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use serde::{Deserialize, Serialize}; // Import the traits
+use itertools::Itertools;
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use indicatif_log_bridge::LogWrapper;
+use log::{debug, error, info, warn};
 
-pub mod address;
-pub mod pdb_store;
-pub mod utils;
+use clap::error::ErrorKind;
+use clap::builder::BoolishValueParser;
+use clap::{ArgAction, Args, CommandFactory, Parser, Subcommand, ValueEnum};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
 
-fn default_version() -> String {
-    "".into()
+use lpus::download::{
+    download_record, generate_download_url, http_client, output_key, parse_record, record_arch, remove_downloaded,
+    remove_partial_downloads, symbol_servers, target_path, version_key, ParsedStores, PdbSource, ProcessOptions,
+    DOWNLOADED_BYTES, MIN_FREE_SPACE, REQUEST_LIMITER,
+};
+use lpus::error::Error;
+use lpus::winbindex::{
+    fetch_dump, for_each_record, get_os_version, open_dump, pe_name_from_dump, RecordData, Update, Version,
+};
+use lpus::diff::{compare_stores, ChangeKind};
+use lpus::format::{MasterFile, PdbIdentity, VersionOffsets, SCHEMA_VERSION};
+use lpus::pdb_store::FieldList;
+use lpus::pe::{CodeView, PeFile};
+use lpus::utils::date::parse_date;
+use lpus::utils::version::{parse_version_bound, Version4};
+use lpus::{codegen, database, format, pdb_store, signature};
+
+#[derive(Debug, Parser)]
+#[command(version, about = "Extract Windows kernel offsets from a winbindex dump")]
+#[command(args_conflicts_with_subcommands = true)]
+#[command(after_help = "Every option can also be set with a KOFF_ environment variable named after it, \
+    e.g. KOFF_OUTPUT_DIR=out or KOFF_JOBS=8, 1/0, true/false, yes/no or on/off for switches. The command line \
+    takes precedence over the environment, which takes precedence over the defaults.")]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Format of log lines on stderr, json writes one object per line with the
+    /// fields of each processed record
+    #[arg(long, global = true, value_enum, default_value_t = LogFormat::Text, env = "KOFF_LOG_FORMAT")]
+    log_format: LogFormat,
+
+    // Without a subcommand the winbindex dump is processed
+    #[command(flatten)]
+    run: RunArgs,
 }
 
-fn default_file_info() -> FileInfo {
-    FileInfo{
-        size: 0,
-        virtualSize: 0,
-        timestamp: 0,
-        version: "".into(),
-    }
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum LogFormat {
+    Text,
+    Json,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Compare the offsets of two processed versions
+    Diff(DiffArgs),
+    /// Print the offset of a struct member or the RVA of a symbol in one version
+    Query(QueryArgs),
+    /// List the structs of a version with their size
+    ListStructs(ListArgs),
+    /// List the symbols of a version with their RVA
+    ListSymbols(ListArgs),
+    /// Print the full layout of one struct, downloading and parsing the version if needed
+    Dump(DumpArgs),
+    /// Extract the fields from a .pdb on disk, without a winbindex dump or download
+    ParseLocal(ParseLocalArgs),
+    /// List the versions in a winbindex dump with how many records each has, without downloading
+    ListVersions(ListVersionsArgs),
+}
+
+#[derive(Debug, Args)]
+struct ListVersionsArgs {
+    /// winbindex dump to read, e.g. ntoskrnl.exe.json or ntoskrnl.exe.json.gz
+    #[arg(default_value = "ntoskrnl.exe.json", env = "KOFF_INPUT")]
+    input: PathBuf,
 }
 
+#[derive(Debug, Args)]
+struct ParseLocalArgs {
+    /// The .pdb to read, e.g. ntkrnlmp.pdb
+    pdb: PathBuf,
+
+    /// Extract the symbols and fields listed in this file, one symbol or Struct.Field
+    /// per line, instead of the built-in set for the pdb name
+    #[arg(long, value_name = "FILE", env = "KOFF_FIELDS")]
+    fields: Option<PathBuf>,
+
+    /// Write info.txt and offsets.json here instead of printing the fields
+    #[arg(long, value_name = "DIR", env = "KOFF_OUTPUT_DIR")]
+    output_dir: Option<PathBuf>,
 
-// --- 1. Define the necessary data structures for deserialization ---
+    /// Append a WinDbg dt style layout of the extracted structs
+    #[arg(long, env = "KOFF_DT", action = ArgAction::SetTrue, value_parser = BoolishValueParser::new())]
+    dt: bool,
 
-// The deepest nested structure we need.
-#[derive(Debug, serde::Deserialize)]
-#[serde(rename_all = "camelCase")]
-struct FileInfo {
-    size: u64,
-    virtualSize: u64,
-    timestamp: u64,
-    #[serde(default = "default_version")]
-    version: String, // e.g., "10.0.10240.17914 (th1.180627-1911)"
+    /// Also write an offsets.h with #defines for the extracted fields, needs --output-dir
+    #[arg(long, requires = "output_dir", env = "KOFF_C_HEADER")]
+    #[arg(action = ArgAction::SetTrue, value_parser = BoolishValueParser::new())]
+    c_header: bool,
 }
 
-// The structure holding FileInfo and the deeply nested Windows version information.
-// We only need the outermost map key (the SHA256 hash) and the internal data.
-#[derive(Debug, serde::Deserialize)]
-#[serde(rename_all = "camelCase")]
-struct RecordData {
-    #[serde(default = "default_file_info")]
-    file_info: FileInfo,
-    windows_versions: HashMap<String, HashMap<String, Kbs>>,
+#[derive(Debug, Args)]
+struct DumpArgs {
+    /// Version (e.g. 10.0.22621.1) or path to a .pdb
+    version: String,
+
+    /// Struct to print, e.g. _EPROCESS
+    #[arg(value_name = "STRUCT")]
+    struct_name: String,
+
+    /// winbindex dump the version is looked up in
+    #[arg(long, value_name = "FILE", default_value = "ntoskrnl.exe.json", env = "KOFF_INPUT")]
+    input: PathBuf,
+
+    /// PE file to fetch from the symbol server, derived from --input when omitted
+    #[arg(long, value_name = "NAME", env = "KOFF_PE_NAME")]
+    pe_name: Option<String>,
+
+    /// Architecture of the version when the dump lists several (x64, x86, arm64)
+    #[arg(long, value_name = "ARCH", default_value = "x64", env = "KOFF_ARCH")]
+    arch: String,
+
+    /// Directory where the PE and its parsed pdb are kept
+    #[arg(long, value_name = "DIR", default_value = "files", env = "KOFF_OUTPUT_DIR")]
+    output_dir: PathBuf,
+
+    /// Symbol server to download PEs and PDBs from, repeat for fallbacks tried in order
+    #[arg(long, value_name = "URL", env = "KOFF_SYMBOL_SERVER")]
+    symbol_server: Vec<String>,
+
+    /// How many times a failed download is retried before giving up
+    #[arg(long, value_name = "N", default_value_t = 3, env = "KOFF_RETRIES")]
+    retries: u32,
+
+    /// Proxy for every request, e.g. http://proxy:3128. Without it the HTTPS_PROXY,
+    /// HTTP_PROXY and ALL_PROXY environment variables are used, with NO_PROXY
+    #[arg(long, value_name = "URL", value_parser = parse_proxy, env = "KOFF_PROXY")]
+    proxy: Option<String>,
+
+    /// Refuse a download that would leave less than this free on the output volume,
+    /// in bytes or with a K, M, G or T suffix, e.g. 2G
+    #[arg(long, value_name = "SIZE", value_parser = parse_size, env = "KOFF_MIN_FREE_SPACE")]
+    min_free_space: Option<u64>,
+
+    /// Seconds to wait for a connection to a server
+    #[arg(long, value_name = "SECS", default_value_t = 30, env = "KOFF_CONNECT_TIMEOUT")]
+    connect_timeout: u64,
+
+    /// Seconds a whole download may take, including reading the body
+    #[arg(long, value_name = "SECS", default_value_t = 120, env = "KOFF_TIMEOUT")]
+    timeout: u64,
+
+    /// Match the struct name ignoring case, e.g. _eprocess
+    #[arg(short, long, env = "KOFF_IGNORE_CASE", action = ArgAction::SetTrue, value_parser = BoolishValueParser::new())]
+    ignore_case: bool,
 }
 
-// Struct for the KB update, contains the assembly information.
-#[derive(Debug, serde::Deserialize)]
-#[serde(rename_all = "camelCase")]
-struct Kbs {
-    // assemblies: HashMap<String, Assembly>,
-    // We don't need the updateInfo field for this task, but it must be included
-    // or ignored if present. Since we ignore it below, we omit it here for simplicity.
+#[derive(Debug, Args)]
+struct ListArgs {
+    /// Version (e.g. 10.0.22621.1), or path to a .pdb or an offsets.json
+    version: String,
+
+    /// Only list names containing this text, ignoring case
+    #[arg(long, value_name = "TEXT", env = "KOFF_GREP")]
+    grep: Option<String>,
+
+    /// Directory where the version was processed
+    #[arg(long, value_name = "DIR", default_value = "files", env = "KOFF_OUTPUT_DIR")]
+    output_dir: PathBuf,
 }
 
-// Struct for the Assembly information.
-#[derive(Debug, serde::Deserialize)]
-#[serde(rename_all = "camelCase")]
-struct Assembly {
-    attributes: Vec<Attribute>,
+#[derive(Debug, Args)]
+struct QueryArgs {
+    /// Version (e.g. 10.0.22621.1) or path to its offsets.json
+    version: String,
+
+    /// Struct member like _EPROCESS.Token, or a symbol like PsLoadedModuleList
+    name: String,
+
+    /// Directory where the version was processed
+    #[arg(long, value_name = "DIR", default_value = "files", env = "KOFF_OUTPUT_DIR")]
+    output_dir: PathBuf,
+
+    /// Look the version up in this master file instead of the output directory
+    #[arg(long, value_name = "FILE", env = "KOFF_MASTER")]
+    master: Option<PathBuf>,
+
+    /// Match the struct, member and symbol names ignoring case, e.g. _eprocess.token
+    #[arg(short, long, env = "KOFF_IGNORE_CASE", action = ArgAction::SetTrue, value_parser = BoolishValueParser::new())]
+    ignore_case: bool,
 }
 
-// Struct for the Attribute, which contains the filename we need.
-#[derive(Debug, serde::Deserialize)]
-#[serde(rename_all = "camelCase")]
-struct Attribute {
-    #[serde(rename = "sourceName")]
-    source_name: String, // This is the PE filename, e.g., "ntkrnlmp.exe"
-    #[serde(rename = "name")]
-    destination_name: String, // This is the target filename, e.g., "ntoskrnl.exe"
+#[derive(Debug, Args)]
+struct DiffArgs {
+    /// Old version (e.g. 10.0.22621.1) or path to its offsets.json
+    old: String,
+
+    /// New version or path to its offsets.json
+    new: String,
+
+    /// Directory where the versions were processed
+    #[arg(long, value_name = "DIR", default_value = "files", env = "KOFF_OUTPUT_DIR")]
+    output_dir: PathBuf,
+
+    /// Also list the members and symbols whose offset did not change
+    #[arg(long, env = "KOFF_SHOW_ALL", action = ArgAction::SetTrue, value_parser = BoolishValueParser::new())]
+    show_all: bool,
 }
 
+#[derive(Debug, Args)]
+struct RunArgs {
+    /// winbindex dump to process, e.g. ntoskrnl.exe.json or ntoskrnl.exe.json.gz,
+    /// or a directory of dumps to process them all into <version>/<pe_name>/
+    #[arg(default_value = "ntoskrnl.exe.json", env = "KOFF_INPUT")]
+    input: PathBuf,
 
-fn generate_download_url(timestamp: u64, size: u64, pe_name: &str) -> String {
-    // 1. Generate the fileId part
+    /// Process at most N matching records, 0 means no limit
+    #[arg(long, value_name = "N", default_value_t = 0, env = "KOFF_LIMIT")]
+    limit: usize,
 
-    // Timestamp part (8 hex digits, uppercase)
-    let time_hex = format!("{:08X}", timestamp);
+    /// Directory where downloaded files and extracted offsets are written
+    #[arg(long, value_name = "DIR", default_value = "files", env = "KOFF_OUTPUT_DIR")]
+    output_dir: PathBuf,
 
-    // Size part (hex, lowercase)
-    let size_hex = format!("{:x}", size);
+    /// PE file to fetch from the symbol server, derived from INPUT when omitted
+    /// (win32k.sys.json -> win32k.sys)
+    #[arg(long, value_name = "NAME", env = "KOFF_PE_NAME")]
+    pe_name: Option<String>,
 
-    let file_id = format!("{}{}", time_hex, size_hex);
+    /// Download files again even if they are already in the output directory
+    #[arg(long, env = "KOFF_FORCE", action = ArgAction::SetTrue, value_parser = BoolishValueParser::new())]
+    force: bool,
 
-    // 2. Construct the final URL
-    format!(
-        "https://msdl.microsoft.com/download/symbols/{}/{}/{}",
-        pe_name,
-        file_id,
-        pe_name
-    )
+    /// Never use the network: only PEs and pdbs already in the output directory are
+    /// processed, records without them fail
+    #[arg(long, conflicts_with_all = ["force", "fetch"], env = "KOFF_OFFLINE")]
+    #[arg(action = ArgAction::SetTrue, value_parser = BoolishValueParser::new())]
+    offline: bool,
+
+    /// Delete the PE, its pdb and parsed cache once the outputs of a version are written
+    #[arg(long, overrides_with = "keep_pe", env = "KOFF_DELETE_PE")]
+    #[arg(action = ArgAction::SetTrue, value_parser = BoolishValueParser::new())]
+    delete_pe: bool,
+
+    /// Keep the downloaded PEs and pdbs as a cache for later runs (default)
+    #[arg(long, overrides_with = "delete_pe", env = "KOFF_KEEP_PE")]
+    #[arg(action = ArgAction::SetTrue, value_parser = BoolishValueParser::new())]
+    keep_pe: bool,
+
+    /// How many times a failed download is retried before giving up
+    #[arg(long, value_name = "N", default_value_t = 3, env = "KOFF_RETRIES")]
+    retries: u32,
+
+    /// Symbol server to download PEs and PDBs from, repeat for fallbacks tried in order.
+    /// Defaults to the http servers in _NT_SYMBOL_PATH, then Microsoft's server
+    #[arg(long, value_name = "URL", env = "KOFF_SYMBOL_SERVER")]
+    symbol_server: Vec<String>,
+
+    /// Proxy for every request, e.g. http://proxy:3128. Without it the HTTPS_PROXY,
+    /// HTTP_PROXY and ALL_PROXY environment variables are used, with NO_PROXY
+    #[arg(long, value_name = "URL", value_parser = parse_proxy, env = "KOFF_PROXY")]
+    proxy: Option<String>,
+
+    /// Start at most N requests per second to the symbol servers, across all workers
+    #[arg(long, value_name = "N", value_parser = parse_rate_limit, env = "KOFF_RATE_LIMIT")]
+    rate_limit: Option<f64>,
+
+    /// Stop the run before a download would leave less than this free on the output volume,
+    /// in bytes or with a K, M, G or T suffix, e.g. 2G. Records in flight finish first
+    #[arg(long, value_name = "SIZE", value_parser = parse_size, env = "KOFF_MIN_FREE_SPACE")]
+    min_free_space: Option<u64>,
+
+    /// Seconds to wait for a connection to a server
+    #[arg(long, value_name = "SECS", default_value_t = 30, env = "KOFF_CONNECT_TIMEOUT")]
+    connect_timeout: u64,
+
+    /// Seconds a whole download may take, including reading the body
+    #[arg(long, value_name = "SECS", default_value_t = 120, env = "KOFF_TIMEOUT")]
+    timeout: u64,
+
+    /// Number of records downloaded and parsed concurrently
+    #[arg(short, long, value_name = "N", default_value_t = NonZeroUsize::MIN, env = "KOFF_JOBS")]
+    jobs: NonZeroUsize,
+
+    /// Number of concurrent downloads, defaults to --jobs
+    #[arg(long, value_name = "N", env = "KOFF_DOWNLOAD_JOBS")]
+    download_jobs: Option<NonZeroUsize>,
+
+    /// Number of PDBs parsed concurrently, defaults to --jobs
+    #[arg(long, value_name = "N", env = "KOFF_PARSE_JOBS")]
+    parse_jobs: Option<NonZeroUsize>,
+
+    /// Skip checking the downloaded file's size and PE signature
+    #[arg(long, env = "KOFF_NO_VERIFY", action = ArgAction::SetTrue, value_parser = BoolishValueParser::new())]
+    no_verify: bool,
+
+    /// Compare the export RVAs of each PE with the symbols of its PDB and log mismatches
+    #[arg(long, env = "KOFF_VALIDATE_EXPORTS", action = ArgAction::SetTrue, value_parser = BoolishValueParser::new())]
+    validate_exports: bool,
+
+    /// Skip comparing the downloaded file's SHA256 with the winbindex record
+    #[arg(long, env = "KOFF_NO_CHECKSUM", action = ArgAction::SetTrue, value_parser = BoolishValueParser::new())]
+    no_checksum: bool,
+
+    /// Format of the per-version description, text writes info.txt,
+    /// isf writes isf.json, a Volatility 3 symbol table, rekall writes rekall.json,
+    /// a Rekall profile
+    #[arg(long, value_enum, default_value_t = Format::Text, env = "KOFF_FORMAT")]
+    format: Format,
+
+    /// Extract the symbols and fields listed in this file, one symbol or Struct.Field
+    /// per line, instead of the built-in default set
+    #[arg(long, value_name = "FILE", env = "KOFF_FIELDS")]
+    fields: Option<PathBuf>,
+
+    /// Append a WinDbg dt style layout of the extracted structs to info.txt
+    #[arg(long, env = "KOFF_DT", action = ArgAction::SetTrue, value_parser = BoolishValueParser::new())]
+    dt: bool,
+
+    /// Add to an existing info.txt, e.g. with notes of its own, instead of replacing it.
+    /// Nothing is added when it already ends with what this run writes
+    #[arg(long, env = "KOFF_APPEND_INFO", action = ArgAction::SetTrue, value_parser = BoolishValueParser::new())]
+    append_info: bool,
+
+    /// Also write an offsets.h with #defines for the extracted fields
+    #[arg(long, env = "KOFF_C_HEADER", action = ArgAction::SetTrue, value_parser = BoolishValueParser::new())]
+    c_header: bool,
+
+    /// Write a byte signature of these functions to offsets.json, comma separated,
+    /// with wildcards for relocated and pc-relative operands, for pattern scanning
+    #[arg(long, value_name = "FUNCTIONS", value_delimiter = ',', env = "KOFF_SIGNATURES")]
+    signatures: Vec<String>,
+
+    /// How many bytes from the start of the function a signature covers
+    #[arg(long, value_name = "N", default_value_t = 32, requires = "signatures", env = "KOFF_SIGNATURE_LENGTH")]
+    signature_length: usize,
+
+    /// Also write an offsets.rs with a module of consts per version in the master file
+    #[arg(long, env = "KOFF_RUST_MODULE", action = ArgAction::SetTrue, value_parser = BoolishValueParser::new())]
+    rust_module: bool,
+
+    /// Also write timeline.csv and timeline.md, the offset of every extracted field
+    /// across all versions in the master file ordered by build
+    #[arg(long, env = "KOFF_TIMELINE", action = ArgAction::SetTrue, value_parser = BoolishValueParser::new())]
+    timeline: bool,
+
+    /// Also write changelog.md, for every release in the master file what each build
+    /// changed in the extracted structs compared to the build before
+    #[arg(long, env = "KOFF_CHANGELOG", action = ArgAction::SetTrue, value_parser = BoolishValueParser::new())]
+    changelog: bool,
+
+    /// Also store the offsets in this SQLite database, created if missing
+    #[arg(long, value_name = "FILE", env = "KOFF_SQLITE")]
+    sqlite: Option<PathBuf>,
+
+    /// Download the dump for the PE from winbindex instead of reading INPUT,
+    /// it is cached in the output directory
+    #[arg(long, env = "KOFF_FETCH", action = ArgAction::SetTrue, value_parser = BoolishValueParser::new())]
+    fetch: bool,
+
+    /// Only process named Windows releases, skipping Insider and unknown builds
+    #[arg(long, env = "KOFF_SKIP_UNKNOWN", action = ArgAction::SetTrue, value_parser = BoolishValueParser::new())]
+    skip_unknown: bool,
+
+    /// Skip records older than this version, e.g. 10.0.22000 for Windows 11 only
+    #[arg(long, value_name = "VERSION", value_parser = parse_min_version, env = "KOFF_MIN_VERSION")]
+    min_version: Option<Version4>,
+
+    /// Skip records newer than this version, 10.0.19045 includes every revision of the build
+    #[arg(long, value_name = "VERSION", value_parser = parse_max_version, env = "KOFF_MAX_VERSION")]
+    max_version: Option<Version4>,
+
+    /// Only process these versions, a comma separated list of prefixes like
+    /// 10.0.22621,10.0.22631.2861. Other records are skipped before any download
+    #[arg(long, value_name = "VERSIONS", value_delimiter = ',', value_parser = parse_version_prefix)]
+    #[arg(env = "KOFF_VERSIONS")]
+    versions: Vec<RangeInclusive<Version4>>,
+
+    /// Skip records built before this Unix time. The build time is the PE TimeDateStamp,
+    /// which on Windows 10 and later is often a hash rather than a time; a stamp that
+    /// cannot be a time falls back to the release date of the first update shipping it
+    #[arg(long, value_name = "UNIX", env = "KOFF_SINCE_TIMESTAMP")]
+    since_timestamp: Option<u64>,
+
+    /// Like --since-timestamp, from midnight UTC of a YYYY-MM-DD date
+    #[arg(long, value_name = "DATE", value_parser = parse_since_date, conflicts_with = "since_timestamp")]
+    #[arg(env = "KOFF_SINCE_DATE")]
+    since_date: Option<u64>,
+
+    /// Only process releases whose name contains this, case insensitive, e.g. "Windows 11 23H2"
+    /// or "24H2". Builds without a name only match a pattern with "build" in it, like
+    /// "build 26200". Can be given more than once
+    #[arg(long, value_name = "NAME", env = "KOFF_MATCH_CODENAME")]
+    match_codename: Vec<String>,
+
+    /// Print the download URL and target path of every selected record,
+    /// without downloading or writing anything
+    #[arg(long, env = "KOFF_DRY_RUN", action = ArgAction::SetTrue, value_parser = BoolishValueParser::new())]
+    dry_run: bool,
+
+    /// Compress the master file into all-offsets.json.gz, or .zst with --compress=zstd
+    #[arg(long, value_enum, value_name = "ALGO", num_args = 0..=1, require_equals = true, default_missing_value = "gzip")]
+    #[arg(env = "KOFF_COMPRESS")]
+    compress: Option<Compression>,
+
+    /// Skip versions already in all-offsets.json, unless --force is given
+    #[arg(long, env = "KOFF_INCREMENTAL", action = ArgAction::SetTrue, value_parser = BoolishValueParser::new())]
+    incremental: bool,
+
+    /// Skip versions whose PE and offsets.json are already in the output directory,
+    /// unless --force is given. Does not need all-offsets.json to have been written
+    #[arg(long, env = "KOFF_RESUME", action = ArgAction::SetTrue, value_parser = BoolishValueParser::new())]
+    resume: bool,
+
+    /// List every skipped record with its SHA256 and the reason it was skipped
+    #[arg(short, long, env = "KOFF_VERBOSE", action = ArgAction::SetTrue, value_parser = BoolishValueParser::new())]
+    verbose: bool,
+
+    /// Exit successfully even when some records failed to process
+    #[arg(long, alias = "ignore-errors", env = "KOFF_KEEP_GOING")]
+    #[arg(action = ArgAction::SetTrue, value_parser = BoolishValueParser::new())]
+    keep_going: bool,
 }
 
-/// Downloads a file from a URL and saves it to a specified path.
-fn download_file(url: &str, target_path: &Path) -> Result<(), Box<dyn std::error::Error>> {
-    println!("Downloading from: {}", url);
+fn parse_proxy(proxy: &str) -> Result<String, String> {
+    reqwest::Proxy::all(proxy)
+        .map(|_| proxy.to_string())
+        .map_err(|_| format!("{} is not a proxy URL like http://proxy:3128", proxy))
+}
 
-    // Make a blocking GET request
-    let response = reqwest::blocking::get(url)?;
+fn parse_rate_limit(rate: &str) -> Result<f64, String> {
+    match rate.parse::<f64>() {
+        Ok(rate) if rate > 0.0 && rate.is_finite() => Ok(rate),
+        _ => Err(format!("{} is not a positive number of requests per second", rate)),
+    }
+}
+
+fn parse_size(size: &str) -> Result<u64, String> {
+    let (number, shift) = match size.char_indices().last() {
+        Some((index, 'K' | 'k')) => (&size[..index], 10),
+        Some((index, 'M' | 'm')) => (&size[..index], 20),
+        Some((index, 'G' | 'g')) => (&size[..index], 30),
+        Some((index, 'T' | 't')) => (&size[..index], 40),
+        _ => (size, 0),
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|number| number.checked_mul(1 << shift))
+        .ok_or(format!("{} is not a size like 500M or 2G", size))
+}
+
+fn parse_min_version(version: &str) -> Result<Version4, String> {
+    parse_version_bound(version, 0).ok_or(format!("{} is not a version like 10.0.22000", version))
+}
+
+fn parse_max_version(version: &str) -> Result<Version4, String> {
+    parse_version_bound(version, u32::MAX).ok_or(format!("{} is not a version like 10.0.22000", version))
+}
+
+fn parse_since_date(date: &str) -> Result<u64, String> {
+    parse_date(date).ok_or(format!("{} is not a date like 2025-09-29", date))
+}
+
+// 10.0.22621 covers every revision of the build, 10.0.2262 does not match it
+fn parse_version_prefix(version: &str) -> Result<RangeInclusive<Version4>, String> {
+    match (parse_version_bound(version, 0), parse_version_bound(version, u32::MAX)) {
+        (Some(first), Some(last)) => Ok(first..=last),
+        _ => Err(format!("{} is not a version like 10.0.22621", version)),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Format {
+    Text,
+    Isf,
+    Rekall,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Compression {
+    Gzip,
+    Zstd,
+}
+
+/// all-offsets.json in output_dir, with the extension of its compression
+fn master_file(output_dir: &Path, compress: Option<Compression>) -> PathBuf {
+    output_dir.join(match compress {
+        None => "all-offsets.json",
+        Some(Compression::Gzip) => "all-offsets.json.gz",
+        Some(Compression::Zstd) => "all-offsets.json.zst",
+    })
+}
 
-    if !response.status().is_success() {
-        return Err(format!("Failed to download file. Status: {}", response.status()).into());
+/// Offsets of every version seen so far, keyed by version.
+/// An existing master file is extended so incremental dumps add to the dataset.
+/// A .gz or .zst master is decompressed while reading.
+fn load_master(master_path: &Path) -> Result<serde_json::Map<String, serde_json::Value>, Box<dyn std::error::Error>> {
+    if !master_path.exists() {
+        return Ok(serde_json::Map::new());
     }
+    let file = io::BufReader::new(File::open(master_path)?);
+    let reader: Box<dyn io::Read> = match master_path.extension().and_then(|ext| ext.to_str()) {
+        Some("gz") => Box::new(GzDecoder::new(file)),
+        Some("zst") => Box::new(zstd::Decoder::with_buffer(file)?),
+        _ => Box::new(file),
+    };
+    serde_json::from_reader(reader)
+        .map_err(|e| e.to_string())
+        .and_then(format::migrate_master)
+        .map_err(|e| format!("{} is not a valid master file: {}", master_path.display(), e).into())
+}
 
-    // Ensure the parent directory exists
-    if let Some(parent) = target_path.parent() {
-        fs::create_dir_all(parent)?;
+fn write_master(
+    master_path: &Path,
+    master: &serde_json::Map<String, serde_json::Value>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let master = &MasterFile {
+        schema_version: SCHEMA_VERSION,
+        versions: master,
+    };
+    let file = io::BufWriter::new(File::create(master_path)?);
+    match master_path.extension().and_then(|ext| ext.to_str()) {
+        Some("gz") => {
+            let mut encoder = GzEncoder::new(file, flate2::Compression::default());
+            serde_json::to_writer_pretty(&mut encoder, master)?;
+            encoder.finish()?.flush()?;
+        }
+        Some("zst") => {
+            let mut encoder = zstd::Encoder::new(file, 0)?;
+            serde_json::to_writer_pretty(&mut encoder, master)?;
+            encoder.finish()?.flush()?;
+        }
+        _ => {
+            let mut file = file;
+            serde_json::to_writer_pretty(&mut file, master)?;
+            file.flush()?;
+        }
     }
+    Ok(())
+}
 
-    // Save the file
-    let mut file = fs::File::create(target_path)?;
-    file.write_all(&response.bytes()?)?;
+fn load_offsets(version: &str, output_dir: &Path) -> Result<pdb_store::PdbStore, Box<dyn std::error::Error>> {
+    // Either a path to an offsets.json or a version processed under output_dir
+    let path = Path::new(version);
+    let path = if path.is_file() {
+        path.to_path_buf()
+    } else {
+        output_dir.join(version).join("offsets.json")
+    };
+    let file = File::open(&path).map_err(|e| format!("cannot open {}: {}", path.display(), e))?;
+    let offsets = format::migrate_offsets(serde_json::from_reader(file)?)
+        .map_err(|e| format!("{} is not a valid offsets.json: {}", path.display(), e))?;
+    pdb_store::PdbStore::from_json(&offsets)
+}
 
-    println!("Successfully downloaded and saved to: {}", target_path.display());
+/// The full store of a version from its downloaded pdb, which has every struct
+/// with its size. Falls back to offsets.json when the pdb is not around.
+fn load_pdb_store(version: &str, output_dir: &Path) -> Result<pdb_store::PdbStore, Box<dyn std::error::Error>> {
+    let path = Path::new(version);
+    if path.is_file() && path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("pdb")) {
+        return Ok(pdb_store::read_pdb(path)?);
+    }
+    let pdb = fs::read_dir(output_dir.join(version))
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .find(|path| path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("pdb")));
+    match pdb {
+        Some(pdb) => Ok(pdb_store::read_pdb(&pdb)?),
+        None => load_offsets(version, output_dir),
+    }
+}
 
+fn matches_grep(name: &str, grep: &Option<String>) -> bool {
+    grep.as_ref()
+        .is_none_or(|grep| name.to_lowercase().contains(&grep.to_lowercase()))
+}
+
+fn list_structs(args: ListArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let store = load_pdb_store(&args.version, &args.output_dir)?;
+    for (struct_name, member_info) in store.structs.iter().sorted_by(|(a, _), (b, _)| a.cmp(b)) {
+        if !matches_grep(struct_name, &args.grep) {
+            continue;
+        }
+        // offsets.json does not keep the struct size
+        match member_info.get("struct_size") {
+            Some((_, size)) => println!("0x{:x} {}", size, struct_name),
+            None => println!("? {}", struct_name),
+        }
+    }
     Ok(())
 }
 
-fn process_entry(sha256_hash: &String, record: &RecordData) -> Result<pdb_store::PdbStore, Box<dyn std::error::Error>> {
-    println!("Processing record for SHA256: {}", sha256_hash);
+fn list_symbols(args: ListArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let store = load_pdb_store(&args.version, &args.output_dir)?;
+    for (name, rva) in store.symbols.iter().sorted_by(|(a, _), (b, _)| a.cmp(b)) {
+        if matches_grep(name, &args.grep) {
+            println!("0x{:x} {}", rva, name);
+        }
+    }
+    Ok(())
+}
 
-    // --- 4. Extract required fields ---
-    let timestamp = record.file_info.timestamp;
-    let size = record.file_info.virtualSize;
+/// The store of one version from the dump, downloaded and parsed the same way
+/// `run` does so the PE and parsed pdb are reused from and kept in output_dir.
+fn fetch_version_store(args: &DumpArgs) -> Result<Arc<pdb_store::PdbStore>, Box<dyn std::error::Error>> {
+    let Some(pe_name) = args.pe_name.clone().or_else(|| pe_name_from_dump(&args.input)) else {
+        return Err(format!("cannot derive the PE name from {}, pass --pe-name", args.input.display()).into());
+    };
+    let mut found = None;
+    for_each_record(open_dump(&args.input)?, |sha256_hash, record| {
+        let version = record.file_version().map(|version| version.to_string());
+        if version.as_deref() == Some(args.version.as_str())
+            && record_arch(&record).is_ok_and(|arch| arch == args.arch)
+        {
+            found = Some((sha256_hash, record));
+            return ControlFlow::Break(());
+        }
+        ControlFlow::Continue(())
+    })?;
+    let Some((sha256_hash, record)) = found else {
+        return Err(format!("{} ({}) is not in {}", args.version, args.arch, args.input.display()).into());
+    };
 
-    // Extract the core version number (e.g., "10.0.10240.17914")
-    let version = record.file_info.version.split_whitespace().next().unwrap_or("unknown");
+    let symbol_servers = symbol_servers(&args.symbol_server);
+    if let Some(min_free_space) = args.min_free_space {
+        MIN_FREE_SPACE.store(min_free_space, Ordering::Relaxed);
+    }
+    let client = http_client(
+        Duration::from_secs(args.connect_timeout),
+        Duration::from_secs(args.timeout),
+        args.proxy.as_deref(),
+    )?;
+    let parsed = ParsedStores::default();
+    // the PE is kept in the output_dir run uses, so it is checked the same way
+    let options = ProcessOptions {
+        client: &client,
+        symbol_servers: &symbol_servers,
+        pe_name: &pe_name,
+        output_dir: &args.output_dir,
+        force: false,
+        retries: args.retries,
+        verify: true,
+        checksum: true,
+        validate_exports: false,
+        parsed: &parsed,
+        pe_dir: false,
+        offline: false,
+    };
+    let downloaded = download_record(&sha256_hash, &record, &options)?;
+    let (store, _) = parse_record(&downloaded, &options)?;
+    Ok(store)
+}
 
-    let pe_name = "ntoskrnl.exe";
-    // The target filename is what you specified in the path: ntoskrnl.exe
-    let target_filename = "ntoskrnl.exe";
+fn dump(args: DumpArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let path = Path::new(&args.version);
+    let store = if path.is_file() && path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("pdb")) {
+        Arc::new(pdb_store::read_pdb(path)?)
+    } else {
+        fetch_version_store(&args)?
+    };
+    let struct_name = if args.ignore_case {
+        store.struct_name_ignore_case(&args.struct_name)?
+    } else {
+        &args.struct_name
+    };
+    store.dump_struct(struct_name, &mut io::stdout().lock())?;
+    Ok(())
+}
 
-    println!("- Timestamp: {}", timestamp);
-    println!("- File Size: {}", size);
-    println!("- Version: {}", version);
-    println!("- PE Name (used for URL): {}", pe_name);
+fn list_versions(args: ListVersionsArgs) -> Result<(), Box<dyn std::error::Error>> {
+    // version -> (codename, records, architectures)
+    let mut versions: BTreeMap<Version4, (String, usize, BTreeSet<&str>)> = BTreeMap::new();
+    let mut unversioned = 0;
+    for_each_record(open_dump(&args.input)?, |_, record| {
+        match record.file_version() {
+            Some(version) => {
+                let (_, count, arches) = versions.entry(version).or_insert_with(|| {
+                    let codename = get_os_version(&record).map(|os_version| os_version.codename);
+                    (codename.unwrap_or_default(), 0, BTreeSet::new())
+                });
+                *count += 1;
+                arches.insert(record_arch(&record).unwrap_or("unknown"));
+            }
+            None => unversioned += 1,
+        }
+        ControlFlow::Continue(())
+    })?;
 
-    // --- 5. Generate the URL and target path ---
-    let download_url = generate_download_url(timestamp, size, pe_name);
+    for (version, (codename, count, arches)) in &versions {
+        println!("{:<20} {:>4} {:<12} {}", version, count, arches.iter().join(","), codename);
+    }
+    info!(
+        "{} versions in {}, {} records without a version",
+        versions.len(), args.input.display(), unversioned
+    );
+    Ok(())
+}
 
-    // Target path: files/<version>/ntoskrnl.exe
-    let target_path = Path::new("files").join(version).join(target_filename);
+fn parse_local(args: ParseLocalArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let store = pdb_store::read_pdb(&args.pdb).map_err(|e| format!("cannot read {}: {}", args.pdb.display(), e))?;
+    let FieldList { symbols, fields } = match &args.fields {
+        Some(path) => pdb_store::load_fields(path)?,
+        // the lists are picked by PE name, ntkrnlmp.pdb -> ntkrnlmp.dll gets the kernel one
+        None => {
+            let pe_name = args.pdb.with_extension("dll");
+            pdb_store::default_fields_for(&pe_name.file_name().unwrap_or_default().to_string_lossy())
+        }
+    };
+    let title = args.pdb.file_name().unwrap_or_default().to_string_lossy().into_owned();
+    let write_info = |mut stream: &mut dyn Write| -> io::Result<()> {
+        writeln!(stream, "{}", title)?;
+        store.print_information(&symbols, &fields, &mut stream)?;
+        if args.dt {
+            for struct_name in fields.iter().map(|(struct_name, _)| struct_name).sorted().dedup() {
+                writeln!(stream)?;
+                store.dump_struct(struct_name, &mut stream)?;
+            }
+        }
+        Ok(())
+    };
 
-    println!("- Download URL: {}", download_url);
-    println!("- Target Path: {}", target_path.display());
+    let Some(dir) = &args.output_dir else {
+        return Ok(write_info(&mut io::stdout().lock())?);
+    };
+    fs::create_dir_all(dir)?;
+    let mut info = Vec::new();
+    write_info(&mut info)?;
+    write_if_changed(&dir.join("info.txt"), &info)?;
 
-    download_file(&download_url, &target_path)?;
+    // without a dump there is no version to go with, only the offsets
+    let mut offsets = serde_json::to_value(store.to_offsets())?;
+    if let Some(offsets) = offsets.as_object_mut() {
+        offsets.insert("schema_version".into(), SCHEMA_VERSION.into());
+    }
+    write_if_changed(&dir.join("offsets.json"), &serde_json::to_vec_pretty(&offsets)?)?;
 
-    let pdb_store = pdb_store::parse_pdb(&target_path)?;
-    // println!("pdb store symbols {:?}\n", pdb_store.symbols);
-    // println!("pdb store structs {:?}\n", pdb_store.structs);
-    Ok(pdb_store)
+    if args.c_header {
+        let mut header = Vec::new();
+        codegen::write_c_header(&store, &fields, &title, &mut header)?;
+        write_if_changed(&dir.join("offsets.h"), &header)?;
+    }
+    info!("Wrote the offsets of {} to {}", args.pdb.display(), dir.display());
+    Ok(())
 }
 
+fn diff(args: DiffArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let old = load_offsets(&args.old, &args.output_dir)?;
+    let new = load_offsets(&args.new, &args.output_dir)?;
 
-struct Version{
-    codename: String,
-    version: String,
+    let changes = compare_stores(&old, &new);
+    let changes = if args.show_all { changes } else { changes.only_changed() };
+
+    let line = |name: &str, kind: ChangeKind, old: Option<u64>, new: Option<u64>| match kind {
+        ChangeKind::Changed => format!("  ~ {} 0x{:x} -> 0x{:x}", name, old.unwrap_or(0), new.unwrap_or(0)),
+        ChangeKind::Removed => format!("  - {} 0x{:x}", name, old.unwrap_or(0)),
+        ChangeKind::Added => format!("  + {} 0x{:x}", name, new.unwrap_or(0)),
+        ChangeKind::Unchanged => format!("  = {} 0x{:x}", name, new.unwrap_or(0)),
+    };
+    for (struct_name, fields) in &changes.fields.iter().chunk_by(|field| &field.struct_name) {
+        println!("{}", struct_name);
+        for field in fields {
+            if field.is_retyped() {
+                let (old_type, new_type) = (field.old_type.as_deref(), field.new_type.as_deref());
+                let offset = field.new.unwrap_or(0);
+                println!(
+                    "  ~ {} 0x{:x} {} -> {}",
+                    field.member, offset, old_type.unwrap_or_default(), new_type.unwrap_or_default()
+                );
+            } else {
+                println!("{}", line(&field.member, field.kind, field.old, field.new));
+            }
+        }
+    }
+    if !changes.symbols.is_empty() {
+        println!("symbols");
+        for symbol in &changes.symbols {
+            println!("{}", line(&symbol.name, symbol.kind, symbol.old, symbol.new));
+        }
+    }
+    if !changes.sizes.is_empty() {
+        println!("struct sizes");
+        for size in &changes.sizes {
+            println!("{}", line(&size.struct_name, size.kind, size.old, size.new));
+        }
+    }
+    Ok(())
 }
 
-fn get_os_version(record: &RecordData) -> Option<Version> {
-    let os_versions = vec![
-        Version{
-            codename: "Windows 11 24H2".into(),
-            version: "10.0.26100".into()
-        },
-        Version{
-            codename: "Windows 11 23H2".into(),
-            version: "10.0.22631".into()
-        },
-        Version{
-            codename: "Windows 11 22H2".into(),
-            version: "10.0.22621".into()
-        },
-        Version{
-            codename: "Windows 11 21H2".into(),
-            version: "10.0.22000".into()
-        },
-        Version{
-            codename: "Windows 10 22H2".into(),
-            version: "10.0.19045".into()
+fn query(args: QueryArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let store = match &args.master {
+        Some(master_path) => {
+            let master = load_master(master_path)?;
+            let offsets = master
+                .get(&args.version)
+                .ok_or(format!("{} is not in {}", args.version, master_path.display()))?;
+            pdb_store::PdbStore::from_json(offsets)?
+        }
+        None => load_offsets(&args.version, &args.output_dir)?,
+    };
+
+    let name = if args.ignore_case {
+        let resolved = match args.name.split_once('.') {
+            Some((struct_name, member)) => store
+                .field_name_ignore_case(struct_name, member)
+                .map(|(struct_name, member)| format!("{}.{}", struct_name, member)),
+            None => store.symbol_name_ignore_case(&args.name).map(str::to_string),
+        };
+        match resolved {
+            Ok(name) => name,
+            Err(e) => {
+                eprintln!("{} in {}", e, args.version);
+                std::process::exit(1);
+            }
+        }
+    } else {
+        args.name.clone()
+    };
+    let found = match name.split_once('.') {
+        Some((struct_name, member)) => store.get_field(struct_name, member).map(|field| {
+            let location = match field.location {
+                pdb_store::MemberLocation::Byte(offset) => format!("0x{:x}", offset),
+                pdb_store::MemberLocation::Bitfield { byte, bit, len } => {
+                    format!("0x{:x} bit {} len {}", byte, bit, len)
+                }
+            };
+            // offsets.json written before types were recorded has none
+            match field.type_name.as_str() {
+                "" => location,
+                type_name => format!("{} {}", location, type_name),
+            }
+        }),
+        None => store.get_symbol_rva(&name).map(|rva| format!("0x{:x}", rva)),
+    };
+
+    match found {
+        Some(found) => println!("{}", found),
+        None => {
+            eprintln!("{} not found in {}", args.name, args.version);
+            std::process::exit(1);
+        }
+    }
+    Ok(())
+}
+
+/// Write the outputs of one processed version into output_dir/key and return
+/// the offsets that go into the master file.
+#[allow(clippy::too_many_arguments)]
+fn write_version(
+    cli: &RunArgs,
+    pe_name: &str,
+    symbols: &[String],
+    fields: &[(String, String)],
+    key: &str,
+    version: &str,
+    arch: &str,
+    os_version: &Version,
+    codeview: Option<&CodeView>,
+    pdb_source: PdbSource,
+    updates: &[Update],
+    store: &pdb_store::PdbStore,
+) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
+    let dir = cli.output_dir.join(key);
+    let title = format!("{} - {} ({})", os_version.codename, version, arch);
+    // Outputs are rendered in memory and only written when they changed, so a
+    // re-run leaves the files of a version alone
+    match cli.format {
+        Format::Text => {
+            let mut info = Vec::new();
+            writeln!(&mut info, "{}", title)?;
+            store.print_information(symbols, fields, &mut info)?;
+            if cli.dt {
+                for struct_name in fields.iter().map(|(struct_name, _)| struct_name).sorted().dedup() {
+                    writeln!(&mut info)?;
+                    store.dump_struct(struct_name, &mut info)?;
+                }
+            }
+            if cli.append_info {
+                append_if_missing(&dir.join("info.txt"), &info)?;
+            } else {
+                write_if_changed(&dir.join("info.txt"), &info)?;
+            }
+        }
+        Format::Isf => {
+            // the PDB identity for the ISF metadata comes from the downloaded PE
+            let pe = PeFile::open(&dir.join(pe_name))?;
+            let mut isf = Vec::new();
+            codegen::write_isf(store, codeview, pe.machine, &mut isf)?;
+            write_if_changed(&dir.join("isf.json"), &isf)?;
+        }
+        Format::Rekall => {
+            let pe = PeFile::open(&dir.join(pe_name))?;
+            let mut rekall = Vec::new();
+            codegen::write_rekall(store, codeview, pe.machine, &mut rekall)?;
+            write_if_changed(&dir.join("rekall.json"), &rekall)?;
+        }
+    }
+
+    let offsets = VersionOffsets {
+        schema_version: SCHEMA_VERSION,
+        codename: os_version.codename.clone(),
+        version: version.to_string(),
+        arch: arch.to_string(),
+        pdb: codeview.map(|codeview| PdbIdentity {
+            name: codeview.pdb_name.clone(),
+            guid: codeview.formatted_guid(),
+            age: codeview.age,
+        }),
+        pdb_source: Some(pdb_source),
+        updates: updates.to_vec(),
+        signatures: if cli.signatures.is_empty() {
+            BTreeMap::new()
+        } else {
+            function_signatures(&cli.signatures, cli.signature_length, &dir.join(pe_name), store)?
         },
-        // Version{
-        //     codename: "Windows 10 21H2",
-        //     version: "10.0.19044"
-        // },
-        // Version{
-        //     codename: "Windows 10 20H2",
-        //     version: "10.0.19042"
-        // },
-        // Version{
-        //     codename: "Windows 10 2004",
-        //     version: "10.0.19041"
-        // },
-        // Version{
-        //     codename: "Windows 10 1909",
-        //     version: "10.0.18363"
-        // },
-        // Version{
-        //     codename: "Windows 10 1809",
-        //     version: "10.0.17763"
-        // },
-        // Version{
-        //     codename: "Windows 10 1709",
-        //     version: "10.0.16299"
-        // },
-        // Version{
-        //     codename: "Windows 10 1609",
-        //     version: "10.0.14393"
-        // },
-        // Version{
-        //     codename: "Windows 10 1509",
-        //     version: "10.0.10240"
-        // },
-    ];
-    let version = record.file_info.version.split_whitespace().next().unwrap_or("unknown");
-    for os in os_versions.into_iter() {
-        if version.starts_with(&os.version) {
-            return Some(os);
-        }
-    }
-    return None;
+        offsets: store.to_offsets(),
+    };
+    write_if_changed(&dir.join("offsets.json"), &serde_json::to_vec_pretty(&offsets)?)?;
+    let offsets = serde_json::to_value(offsets)?;
+
+    if cli.c_header {
+        let mut header = Vec::new();
+        codegen::write_c_header(store, fields, &title, &mut header)?;
+        write_if_changed(&dir.join("offsets.h"), &header)?;
+    }
+    Ok(offsets)
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let file = File::open("ntoskrnl.exe.json")?;
-    // let file = File::open("test.json")?;
+/// Write content to path unless the file already holds exactly that, so its
+/// modification time only moves when it changed.
+fn write_if_changed(path: &Path, content: &[u8]) -> io::Result<()> {
+    if fs::read(path).is_ok_and(|existing| existing == content) {
+        debug!("{} is unchanged", path.display());
+        return Ok(());
+    }
+    fs::write(path, content)
+}
 
-    let records: HashMap<String, RecordData> = serde_json::from_reader(file)?;
+/// Append content to path, creating it, unless the file already ends with it,
+/// so re-running a version does not repeat it.
+fn append_if_missing(path: &Path, content: &[u8]) -> io::Result<()> {
+    let existing = match fs::read(path) {
+        Ok(existing) => existing,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e),
+    };
+    if existing.ends_with(content) {
+        debug!("{} already ends with this run's information", path.display());
+        return Ok(());
+    }
+    let mut file = fs::OpenOptions::new().create(true).append(true).open(path)?;
+    // a blank line between runs
+    if !existing.is_empty() {
+        writeln!(file)?;
+    }
+    file.write_all(content)
+}
 
-    let mut i = 100;
-    for (sha256_hash, record) in records.into_iter() {
-        let os_version = get_os_version(&record);
-        if os_version.is_none() {
+/// Signatures of the listed functions in the PE, those without a symbol or
+/// outside the file are logged and left out.
+fn function_signatures(
+    functions: &[String],
+    len: usize,
+    pe_path: &Path,
+    store: &pdb_store::PdbStore,
+) -> Result<BTreeMap<String, String>, Box<dyn std::error::Error>> {
+    let pe = PeFile::open(pe_path)?;
+    let relocations = pe.relocations()?;
+    let mut signatures = BTreeMap::new();
+    for function in functions {
+        let Some(rva) = store.get_symbol_rva(function) else {
+            warn!("No symbol {} for a signature in {}", function, pe_path.display());
+            continue;
+        };
+        match signature::function_signature(&pe, &relocations, rva, len) {
+            Ok(signature) => {
+                signatures.insert(function.clone(), signature);
+            }
+            Err(e) => warn!("No signature of {} in {}: {}", function, pe_path.display(), e),
+        }
+    }
+    Ok(signatures)
+}
+
+/// The versions under output_dir with a valid PE and offsets.json, keyed like
+/// output_key with their offsets, as a run that was cut short left them.
+/// With --delete-pe the PE is gone from completed versions, offsets.json is enough.
+fn completed_versions(output_dir: &Path, pe_name: &str, require_pe: bool) -> BTreeMap<String, serde_json::Value> {
+    let mut completed = BTreeMap::new();
+    // <version>[/<arch>][/<pe_name>], a few levels deep at most
+    let mut dirs = vec![(output_dir.to_path_buf(), String::new(), 0)];
+    while let Some((dir, key, depth)) = dirs.pop() {
+        let Ok(entries) = fs::read_dir(&dir) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if depth >= 3 || !path.is_dir() {
+                continue;
+            }
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let key = if key.is_empty() { name } else { format!("{}/{}", key, name) };
+            dirs.push((path, key, depth + 1));
+        }
+        if key.is_empty() || (require_pe && PeFile::open(&dir.join(pe_name)).is_err()) {
             continue;
         }
+        let offsets = File::open(dir.join("offsets.json"))
+            .ok()
+            .and_then(|file| serde_json::from_reader::<_, serde_json::Value>(io::BufReader::new(file)).ok())
+            .and_then(|offsets| format::migrate_offsets(offsets).ok())
+            .filter(|offsets| pdb_store::PdbStore::from_json(offsets).is_ok());
+        match offsets {
+            Some(offsets) => {
+                completed.insert(key, offsets);
+            }
+            None => debug!("{} has no valid offsets.json, it is processed again", dir.display()),
+        }
+    }
+    completed
+}
+
+// Set by the Ctrl-C handler, no new records are started once it is
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+// Which records of a dump get processed
+struct Selection {
+    limit: usize,
+    skip_unknown: bool,
+    min_version: Option<Version4>,
+    max_version: Option<Version4>,
+    // versions listed with --versions, empty for all of them
+    versions: Vec<RangeInclusive<Version4>>,
+    // --since-timestamp or --since-date, with the time of the run to tell stamps
+    // that are hashes, see RecordData::build_time
+    since: Option<(u64, u64)>,
+    // lowercased --match-codename patterns, empty for all releases
+    codenames: Vec<String>,
+    // the PE name when outputs are nested under it, see output_key
+    pe_dir: Option<String>,
+    // versions already in the master file or output directory, skipped with
+    // --incremental and --resume
+    existing: HashSet<String>,
+    // log every skipped record instead of only counting it
+    verbose: bool,
+}
+
+impl Selection {
+    fn matches_codename(&self, os_version: &Version) -> bool {
+        let codename = os_version.codename.to_lowercase();
+        self.codenames.is_empty()
+            || self.codenames.iter().any(|pattern| {
+                // "Windows" would match the fallback name of every unnamed build too
+                codename.contains(pattern.as_str()) && (os_version.is_known() || pattern.contains("build"))
+            })
+    }
+}
+
+// Why select_records passed over a record, counted into Summary::skipped
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum SkipReason {
+    // no version, or one get_os_version does not know
+    UnknownVersion,
+    // outside --min-version, --max-version, --versions or --since-timestamp
+    OutOfRange,
+    // not of --match-codename, or unnamed with --skip-unknown
+    Filtered,
+    // after --limit records were selected
+    LimitReached,
+    // a record of the same version and architecture was already selected
+    Duplicate,
+}
+
+impl SkipReason {
+    fn as_str(self) -> &'static str {
+        match self {
+            SkipReason::UnknownVersion => "unknown_version",
+            SkipReason::OutOfRange => "out_of_range",
+            SkipReason::Filtered => "filtered",
+            SkipReason::LimitReached => "limit_reached",
+            SkipReason::Duplicate => "duplicate",
+        }
+    }
+}
+
+// Counts of a run, summed over its dumps
+#[derive(Debug, Default)]
+struct Summary {
+    // records read from the dumps
+    records: usize,
+    // records of a Windows version get_os_version knows
+    matched: usize,
+    // already processed, with --incremental or --resume
+    existing: usize,
+    skipped: BTreeMap<SkipReason, usize>,
+    downloaded: usize,
+    succeeded: usize,
+    // the symbol server has no PE or pdb for them, not counted as failed
+    not_indexed: usize,
+    failed: usize,
+    // a download hit --min-free-space and the run stopped
+    out_of_space: bool,
+    download_times: StageTimes,
+    parse_times: StageTimes,
+}
+
+// When a record was picked up and how long its download and parse took
+#[derive(Debug, Clone, Copy)]
+struct Timings {
+    started: Instant,
+    download: Duration,
+    // None when the download failed
+    parse: Option<Duration>,
+}
+
+// Shortest, longest and total time of one stage over the records that went through it
+#[derive(Debug, Default)]
+struct StageTimes {
+    count: u32,
+    total: Duration,
+    min: Option<Duration>,
+    max: Duration,
+}
+
+impl StageTimes {
+    fn add(&mut self, time: Duration) {
+        self.count += 1;
+        self.total += time;
+        self.min = Some(self.min.map_or(time, |min| min.min(time)));
+        self.max = self.max.max(time);
+    }
+
+    // (min, average, max) in milliseconds
+    fn millis(&self) -> (u64, u64, u64) {
+        let average = self.total.checked_div(self.count).unwrap_or_default();
+        (
+            self.min.unwrap_or_default().as_millis() as u64,
+            average.as_millis() as u64,
+            self.max.as_millis() as u64,
+        )
+    }
+}
+
+impl Summary {
+    // add the counts select_records returns for one dump
+    fn add_selected(&mut self, counts: Summary) {
+        self.records += counts.records;
+        self.matched += counts.matched;
+        self.existing += counts.existing;
+        for (reason, count) in counts.skipped {
+            *self.skipped.entry(reason).or_default() += count;
+        }
+    }
+
+    fn skipped(&self, reason: SkipReason) -> usize {
+        self.skipped.get(&reason).copied().unwrap_or(0)
+    }
+}
+
+/// Stream the records of a dump that have a Windows version to process,
+/// up to `limit` of them. Returns how many records were read, matched,
+/// skipped as already processed and skipped for each SkipReason.
+fn select_records<R, F>(dump: R, selection: &Selection, mut f: F) -> serde_json::Result<Summary>
+where
+    R: std::io::Read,
+    F: FnMut(String, RecordData, Version) -> ControlFlow<()>,
+{
+    let mut selected = 0;
+    // output keys of the selected records
+    let mut selected_keys = HashSet::new();
+    let mut summary = Summary::default();
+    let skip = |summary: &mut Summary, sha256_hash: &str, version: &str, reason: SkipReason| {
+        *summary.skipped.entry(reason).or_default() += 1;
+        if selection.verbose {
+            info!(
+                sha256 = sha256_hash, version = version, reason = reason.as_str();
+                "Skipped {} ({}): {}", sha256_hash, version, reason.as_str()
+            );
+        }
+    };
+    for_each_record(dump, |sha256_hash, record| {
+        summary.records += 1;
+        let Some(version) = record.file_version() else {
+            // records without fileInfo have no version at all, only a bad one is worth a warning
+            if record.file_info.version.is_empty() {
+                debug!("Skipping {}, no version", sha256_hash);
+            } else {
+                warn!("Skipping {}, malformed version {:?}", sha256_hash, record.file_info.version);
+            }
+            skip(&mut summary, &sha256_hash, &record.file_info.version, SkipReason::UnknownVersion);
+            return ControlFlow::Continue(());
+        };
+        let version_name = version.to_string();
+        // matched counts every record of a known version, whatever the filters leave
+        let Some(os_version) = get_os_version(&record) else {
+            skip(&mut summary, &sha256_hash, &version_name, SkipReason::UnknownVersion);
+            return ControlFlow::Continue(());
+        };
+        summary.matched += 1;
+        if selection.min_version.is_some_and(|min| version < min)
+            || selection.max_version.is_some_and(|max| version > max)
+            || !(selection.versions.is_empty() || selection.versions.iter().any(|listed| listed.contains(&version)))
+            || selection
+                .since
+                .is_some_and(|(since, now)| record.build_time(now).is_some_and(|built| built < since))
+        {
+            skip(&mut summary, &sha256_hash, &version_name, SkipReason::OutOfRange);
+            return ControlFlow::Continue(());
+        }
+        // only fileInfo is needed, a record without windowsVersions is still processed
+        if record.windows_versions.is_empty() {
+            warn!("{} ({}) lists no Windows versions", sha256_hash, record.file_info.version);
+        }
+        if (selection.skip_unknown && !os_version.is_known()) || !selection.matches_codename(&os_version) {
+            skip(&mut summary, &sha256_hash, &version_name, SkipReason::Filtered);
+            return ControlFlow::Continue(());
+        }
+        let key = record_arch(&record)
+            .ok()
+            .map(|arch| output_key(&version_name, arch, selection.pe_dir.as_deref()));
+        if let Some(key) = &key {
+            if selection.existing.contains(key) {
+                debug!("Skipping {}, {} is already processed", sha256_hash, version);
+                summary.existing += 1;
+                return ControlFlow::Continue(());
+            }
+            if selected_keys.contains(key) {
+                skip(&mut summary, &sha256_hash, &version_name, SkipReason::Duplicate);
+                return ControlFlow::Continue(());
+            }
+        }
+        // the rest of the dump is still read to count what the limit left out
+        if selected >= selection.limit {
+            skip(&mut summary, &sha256_hash, &version_name, SkipReason::LimitReached);
+            return ControlFlow::Continue(());
+        }
+        f(sha256_hash, record, os_version)?;
+        selected += 1;
+        selected_keys.extend(key);
+        ControlFlow::Continue(())
+    })?;
+    Ok(summary)
+}
+
+// Collects the key-values of a log record, e.g. info!(version = "10.0.1.1"; "...")
+struct JsonFields<'a>(&'a mut serde_json::Map<String, serde_json::Value>);
+
+impl<'kvs> log::kv::VisitSource<'kvs> for JsonFields<'_> {
+    fn visit_pair(&mut self, key: log::kv::Key<'kvs>, value: log::kv::Value<'kvs>) -> Result<(), log::kv::Error> {
+        let mut json = JsonValue(serde_json::Value::Null);
+        value.visit(&mut json)?;
+        self.0.insert(key.to_string(), json.0);
+        Ok(())
+    }
+}
+
+// Numbers stay numbers and a None becomes null, anything else is written as a string
+struct JsonValue(serde_json::Value);
+
+impl<'v> log::kv::VisitValue<'v> for JsonValue {
+    fn visit_any(&mut self, value: log::kv::Value) -> Result<(), log::kv::Error> {
+        self.0 = value.to_string().into();
+        Ok(())
+    }
+
+    fn visit_null(&mut self) -> Result<(), log::kv::Error> {
+        self.0 = serde_json::Value::Null;
+        Ok(())
+    }
+
+    fn visit_u64(&mut self, value: u64) -> Result<(), log::kv::Error> {
+        self.0 = value.into();
+        Ok(())
+    }
+}
+
+fn json_log_line(buf: &mut env_logger::fmt::Formatter, record: &log::Record) -> io::Result<()> {
+    let mut line = serde_json::Map::new();
+    line.insert("timestamp".into(), buf.timestamp().to_string().into());
+    line.insert("level".into(), record.level().as_str().into());
+    line.insert("target".into(), record.target().into());
+    line.insert("message".into(), record.args().to_string().into());
+    let _ = record.key_values().visit(&mut JsonFields(&mut line));
+    writeln!(buf, "{}", serde_json::Value::Object(line))
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let cli = Cli::parse();
+
+    // RUST_LOG=warn for quiet output, RUST_LOG=debug to also see URLs and paths.
+    // Log lines go through the progress bars so they do not tear them.
+    let mut builder = env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"));
+    if cli.log_format == LogFormat::Json {
+        builder.format(json_log_line);
+    }
+    let logger = builder.build();
+    let level = logger.filter();
+    let progress = MultiProgress::new();
+    if !io::stdout().is_terminal() {
+        progress.set_draw_target(ProgressDrawTarget::hidden());
+    }
+    LogWrapper::new(progress.clone(), logger).try_init()?;
+    log::set_max_level(level);
+
+    match cli.command {
+        Some(Command::Diff(args)) => diff(args),
+        Some(Command::Query(args)) => query(args),
+        Some(Command::ListStructs(args)) => list_structs(args),
+        Some(Command::ListSymbols(args)) => list_symbols(args),
+        Some(Command::Dump(args)) => dump(args),
+        Some(Command::ParseLocal(args)) => parse_local(args),
+        Some(Command::ListVersions(args)) => list_versions(args),
+        None => run(cli.run, &progress),
+    }
+}
+
+// A winbindex dump with the PE it lists and the fields to extract from it
+struct DumpSource<'a> {
+    dump: Box<dyn io::Read + Send>,
+    pe_name: &'a str,
+    pe_dir: bool,
+    symbols: &'a [String],
+    fields: &'a [(String, String)],
+}
+
+/// Download, parse and write every selected record of one dump, counting them into summary.
+#[allow(clippy::too_many_arguments)]
+fn process_dump(
+    cli: &RunArgs,
+    client: &reqwest::blocking::Client,
+    symbol_servers: &[String],
+    source: DumpSource,
+    master: &mut serde_json::Map<String, serde_json::Value>,
+    database: &mut Option<database::Database>,
+    progress: &MultiProgress,
+    summary: &mut Summary,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let DumpSource { dump, pe_name, pe_dir, symbols, fields } = source;
+    let limit = if cli.limit == 0 { usize::MAX } else { cli.limit };
+    let mut selection = Selection {
+        limit,
+        skip_unknown: cli.skip_unknown,
+        min_version: cli.min_version,
+        max_version: cli.max_version,
+        versions: cli.versions.clone(),
+        since: cli.since_timestamp.or(cli.since_date).map(|since| {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |now| now.as_secs());
+            (since, now)
+        }),
+        codenames: cli.match_codename.iter().map(|pattern| pattern.to_lowercase()).collect(),
+        pe_dir: pe_dir.then(|| pe_name.to_string()),
+        existing: if cli.incremental && !cli.force {
+            master.keys().cloned().collect()
+        } else {
+            HashSet::new()
+        },
+        verbose: cli.verbose,
+    };
+    if cli.resume && !cli.force {
+        let completed = completed_versions(&cli.output_dir, pe_name, !cli.delete_pe);
+        info!("{} versions of {} are already complete in {}", completed.len(), pe_name, cli.output_dir.display());
+        // the master file may not have been written by the interrupted run
+        for (key, offsets) in completed {
+            selection.existing.insert(key.clone());
+            master.entry(key).or_insert(offsets);
+        }
+    }
+
+    let parsed = ParsedStores::default();
+    let options = ProcessOptions {
+        client,
+        symbol_servers,
+        pe_name,
+        output_dir: &cli.output_dir,
+        force: cli.force,
+        retries: cli.retries,
+        verify: !cli.no_verify,
+        checksum: !cli.no_checksum,
+        validate_exports: cli.validate_exports,
+        parsed: &parsed,
+        pe_dir,
+        offline: cli.offline,
+    };
+
+    if cli.dry_run {
+        // One "<url> <target path>" line per record, easy to diff or turn into a fetch script
+        let counts = select_records(dump, &selection, |_, record, _| {
+            let url = generate_download_url(
+                &symbol_servers[0],
+                record.file_info.timestamp,
+                record.file_info.virtual_size,
+                pe_name,
+            );
+            match target_path(&record, &options) {
+                Ok(path) => println!("{} {}", url, path.display()),
+                Err(e) => error!("Skipping {}: {}", record.file_info.version, e),
+            }
+            ControlFlow::Continue(())
+        })?;
+        summary.add_selected(counts);
+        return Ok(());
+    }
+
+    // Records processed against --limit, the total is unknown without one
+    // since the dump is only read as it goes
+    let (overall, template) = match cli.limit {
+        0 => (ProgressBar::no_length(), "[{elapsed_precise}] {human_pos} records"),
+        _ => (ProgressBar::new(limit as u64), "[{elapsed_precise}] {bar:40} {human_pos}/{human_len} records"),
+    };
+    let overall = progress.add(overall.with_style(ProgressStyle::with_template(template).unwrap()));
+
+    // The dump is streamed on its own thread into a bounded queue, in file order.
+    // Download workers pull records from the queue and hand the downloaded PEs to
+    // the parse workers through a second queue, so a slow parse does not hold up
+    // the network and the other way around. Failures go straight to the results.
+    let downloaded = AtomicUsize::new(0);
+    let download_jobs = cli.download_jobs.unwrap_or(cli.jobs).get();
+    let parse_jobs = cli.parse_jobs.unwrap_or(cli.jobs).get();
+    let (queue_sender, queue) = mpsc::sync_channel(download_jobs);
+    let queue = Mutex::new(queue);
+    let (parse_sender, parse_queue) = mpsc::sync_channel(parse_jobs);
+    let parse_queue = Mutex::new(parse_queue);
+    let (sender, receiver) = mpsc::channel();
+    thread::scope(|scope| {
+        let selection = &selection;
+        let reader = scope.spawn(move || {
+            let mut index = 0;
+            select_records(dump, selection, |sha256_hash, record, os_version| {
+                if INTERRUPTED.load(Ordering::Relaxed)
+                    || queue_sender.send((index, sha256_hash, record, os_version)).is_err()
+                {
+                    return ControlFlow::Break(());
+                }
+                index += 1;
+                ControlFlow::Continue(())
+            })
+        });
+
+        for _ in 0..download_jobs {
+            let (sender, parse_sender) = (sender.clone(), parse_sender.clone());
+            let (queue, options, downloaded) = (&queue, &options, &downloaded);
+            // One spinner per worker with the version it is working on
+            let spinner = progress.add(ProgressBar::new_spinner());
+            spinner.enable_steady_tick(Duration::from_millis(100));
+            scope.spawn(move || {
+                loop {
+                    spinner.set_message("download: waiting");
+                    let next = queue.lock().unwrap().recv();
+                    let Ok((index, sha256_hash, record, os_version)) = next else {
+                        break;
+                    };
+                    // after Ctrl-C the queued records are dropped, only those in flight finish.
+                    // They were all queued before these, so the reordering is not held up.
+                    if INTERRUPTED.load(Ordering::Relaxed) {
+                        continue;
+                    }
+                    spinner.set_message(format!("download: {} - {}", os_version.codename, record.file_info.version));
+                    let started = Instant::now();
+                    let result = download_record(&sha256_hash, &record, options);
+                    let timings = Timings { started, download: started.elapsed(), parse: None };
+                    let sent = match result {
+                        Ok(pe) => {
+                            downloaded.fetch_add(1, Ordering::Relaxed);
+                            parse_sender.send((index, sha256_hash, record, os_version, pe, timings)).is_ok()
+                        }
+                        Err(e) => {
+                            let processed = (Err(e), timings);
+                            sender.send((index, sha256_hash, record, os_version, processed)).is_ok()
+                        }
+                    };
+                    if !sent {
+                        break;
+                    }
+                }
+                spinner.finish_and_clear();
+            });
+        }
+        drop(parse_sender);
 
-        let os_version = os_version.unwrap();
-        if let Ok(store) = process_entry(&sha256_hash, &record) {
-            let version = record.file_info.version.split_whitespace().next().unwrap_or("unknown");
-            let info_file = Path::new("files").join(version).join("info.txt");
+        for _ in 0..parse_jobs {
+            let sender = sender.clone();
+            let (parse_queue, options) = (&parse_queue, &options);
+            let spinner = progress.add(ProgressBar::new_spinner());
+            spinner.enable_steady_tick(Duration::from_millis(100));
+            scope.spawn(move || {
+                loop {
+                    spinner.set_message("parse: waiting");
+                    let next = parse_queue.lock().unwrap().recv();
+                    let Ok((index, sha256_hash, record, os_version, downloaded, mut timings)) = next else {
+                        break;
+                    };
+                    spinner.set_message(format!("parse: {} - {}", os_version.codename, record.file_info.version));
+                    let parse_started = Instant::now();
+                    let store = parse_record(&downloaded, options)
+                        .map(|(store, source)| (store, downloaded.arch, source));
+                    timings.parse = Some(parse_started.elapsed());
+                    let processed = (store, timings);
+                    if sender.send((index, sha256_hash, record, os_version, processed)).is_err() {
+                        break;
+                    }
+                }
+                spinner.finish_and_clear();
+            });
+        }
+        drop(sender);
 
-            let mut file = File::create(info_file).expect("Failed to create file");
-            writeln!(&mut file, "{} - {}", os_version.codename, version);
-            store.print_default_information(&mut file);
+        // Results arrive in completion order, hold them back until every
+        // earlier record is written so the output order is deterministic.
+        let mut pending = BTreeMap::new();
+        let mut next_index = 0;
+        for (index, sha256_hash, record, os_version, processed) in receiver {
+            pending.insert(index, (sha256_hash, record, os_version, processed));
+            while let Some((sha256_hash, record, os_version, (store, timings))) = pending.remove(&next_index) {
+                next_index += 1;
+                overall.inc(1);
+                // select_records only passes on records with a well formed version
+                let version = record.file_version().expect("selected record has a version").to_string();
+                let url = generate_download_url(
+                    &symbol_servers[0],
+                    record.file_info.timestamp,
+                    record.file_info.virtual_size,
+                    pe_name,
+                );
+                // until written, the time waiting in the queues included
+                let duration_ms = timings.started.elapsed().as_millis() as u64;
+                let download_ms = timings.download.as_millis() as u64;
+                let parse_ms = timings.parse.map(|parse| parse.as_millis() as u64);
+                summary.download_times.add(timings.download);
+                if let Some(parse) = timings.parse {
+                    summary.parse_times.add(parse);
+                }
+                let (store, arch, pdb_source) = match store {
+                    Ok(parsed) => parsed,
+                    // expected for some records, only worth a warning
+                    Err(e @ Error::NotIndexed(_)) => {
+                        summary.not_indexed += 1;
+                        warn!(
+                            version = version.as_str(), codename = os_version.codename.as_str(),
+                            sha256 = sha256_hash.as_str(), url = url.as_str(), status = "not_indexed",
+                            error_kind = e.kind(), duration_ms = duration_ms, download_ms = download_ms,
+                            parse_ms = parse_ms;
+                            "Skipping {} ({}): {}", version, sha256_hash, e
+                        );
+                        continue;
+                    }
+                    Err(e) => {
+                        summary.failed += 1;
+                        error!(
+                            version = version.as_str(), codename = os_version.codename.as_str(),
+                            sha256 = sha256_hash.as_str(), url = url.as_str(), status = "failed",
+                            error_kind = e.kind(), duration_ms = duration_ms, download_ms = download_ms,
+                            parse_ms = parse_ms;
+                            "Failed to process {} ({}): {}", version, sha256_hash, e
+                        );
+                        // every later download would fail the same way, stop queuing them
+                        if matches!(&e, Error::Io(e) if e.kind() == io::ErrorKind::StorageFull)
+                            && !summary.out_of_space
+                        {
+                            summary.out_of_space = true;
+                            INTERRUPTED.store(true, Ordering::Relaxed);
+                            error!("Stopping, the output volume is below --min-free-space");
+                        }
+                        continue;
+                    }
+                };
+                let key = output_key(&version, arch, pe_dir.then_some(pe_name));
+                // the PDB identity comes from the downloaded PE
+                let codeview = match PeFile::open(&cli.output_dir.join(&key).join(pe_name)).and_then(|pe| pe.codeview()) {
+                    Ok(codeview) => Some(codeview),
+                    Err(e) => {
+                        warn!("No pdb identity for {}: {}", key, e);
+                        None
+                    }
+                };
+                match write_version(
+                    cli, pe_name, symbols, fields, &key, &version, arch, &os_version, codeview.as_ref(), pdb_source,
+                    &record.updates(), &store,
+                ) {
+                    Ok(offsets) => {
+                        summary.succeeded += 1;
+                        master.insert(key.clone(), offsets);
+                        info!(
+                            version = version.as_str(), codename = os_version.codename.as_str(),
+                            sha256 = sha256_hash.as_str(), url = url.as_str(), status = "ok",
+                            pdb_source = pdb_source.as_str(), duration_ms = duration_ms, download_ms = download_ms,
+                            parse_ms = parse_ms;
+                            "Processed {} in {} ms (download {} ms, parse {} ms)",
+                            key, duration_ms, download_ms, parse_ms.unwrap_or_default()
+                        );
+                    }
+                    Err(e) => {
+                        summary.failed += 1;
+                        error!(
+                            version = version.as_str(), codename = os_version.codename.as_str(),
+                            sha256 = sha256_hash.as_str(), url = url.as_str(), status = "failed",
+                            error_kind = "write", duration_ms = duration_ms, download_ms = download_ms,
+                            parse_ms = parse_ms;
+                            "Failed to write the outputs of {}: {}", key, e
+                        );
+                        continue;
+                    }
+                }
+
+                if let Some(database) = database.as_mut()
+                    && let Err(e) = database.insert_version(
+                        pe_name, &version_key(&version, arch), &os_version.codename, record.file_info.timestamp,
+                        codeview.as_ref(), &store,
+                    )
+                {
+                    error!("Failed to store {} in the database: {}", key, e);
+                }
+                // only once everything of this version is written
+                if cli.delete_pe
+                    && let Err(e) = remove_downloaded(&record, &options)
+                {
+                    warn!("Failed to delete the downloaded files of {}: {}", key, e);
+                }
+            }
         }
 
-        i-=1;
-        if (i == 0) {
+        let counts = reader.join().unwrap()?;
+        summary.add_selected(counts);
+        Ok::<_, serde_json::Error>(())
+    })?;
+    overall.finish();
+    summary.downloaded += downloaded.into_inner();
+    Ok(())
+}
+
+/// Groups of versions in the master file whose offsets, struct sizes and symbols are
+/// all identical, most likely the same build listed twice by winbindex.
+fn duplicate_versions(master: &serde_json::Map<String, serde_json::Value>) -> Vec<Vec<&str>> {
+    let mut groups: BTreeMap<String, Vec<&str>> = BTreeMap::new();
+    for (key, offsets) in master {
+        // codename, version, arch and pdb tell versions apart without being offsets
+        let extracted = serde_json::json!({
+            "structs": offsets["structs"],
+            "sizes": offsets["sizes"],
+            "symbols": offsets["symbols"],
+        });
+        groups.entry(extracted.to_string()).or_default().push(key);
+    }
+    groups.into_values().filter(|group| group.len() > 1).collect()
+}
+
+fn run(cli: RunArgs, progress: &MultiProgress) -> Result<(), Box<dyn std::error::Error>> {
+    let started = Instant::now();
+    let symbol_servers = symbol_servers(&cli.symbol_server);
+    if let Some(rate) = cli.rate_limit {
+        REQUEST_LIMITER.set_rate(rate);
+    }
+    if let Some(min_free_space) = cli.min_free_space {
+        MIN_FREE_SPACE.store(min_free_space, Ordering::Relaxed);
+    }
+    let client = http_client(
+        Duration::from_secs(cli.connect_timeout),
+        Duration::from_secs(cli.timeout),
+        cli.proxy.as_deref(),
+    )?;
+
+    // A directory of dumps is processed dump by dump, each PE gets its own
+    // directory under every version: <version>/<pe_name>/
+    let pe_dir = cli.input.is_dir();
+    let dumps = if pe_dir {
+        if cli.pe_name.is_some() || cli.fetch {
+            Cli::command()
+                .error(
+                    ErrorKind::ArgumentConflict,
+                    "--pe-name and --fetch cannot be used with a directory of dumps",
+                )
+                .exit();
+        }
+        let mut dumps = Vec::new();
+        for entry in fs::read_dir(&cli.input)? {
+            let path = entry?.path();
+            if let Some(pe_name) = pe_name_from_dump(&path)
+                && path.is_file()
+            {
+                dumps.push((path, pe_name));
+            }
+        }
+        dumps.sort();
+        info!("Found {} dumps in {}", dumps.len(), cli.input.display());
+        dumps
+    } else {
+        let pe_name = match cli.pe_name.clone().or_else(|| pe_name_from_dump(&cli.input)) {
+            Some(pe_name) => pe_name,
+            None => Cli::command()
+                .error(
+                    ErrorKind::MissingRequiredArgument,
+                    format!("cannot derive the PE name from {}, pass --pe-name", cli.input.display()),
+                )
+                .exit(),
+        };
+        let input = if cli.fetch {
+            fetch_dump(&client, &pe_name, &cli.output_dir, cli.retries)?
+        } else {
+            cli.input.clone()
+        };
+        vec![(input, pe_name)]
+    };
+
+    let master_path = master_file(&cli.output_dir, cli.compress);
+    // a master written with another --compress is carried over
+    let previous_master = [None, Some(Compression::Gzip), Some(Compression::Zstd)]
+        .into_iter()
+        .map(|compress| master_file(&cli.output_dir, compress))
+        .find(|path| *path != master_path && path.exists());
+    let mut master = match previous_master {
+        Some(previous) if !master_path.exists() => {
+            info!("Reading the master file from {}", previous.display());
+            load_master(&previous)?
+        }
+        _ => load_master(&master_path)?,
+    };
+    let mut database = None;
+    // The first Ctrl-C lets the records in flight finish and the master file be
+    // written, a second one quits right away
+    ctrlc::set_handler(|| {
+        if INTERRUPTED.swap(true, Ordering::Relaxed) {
+            std::process::exit(130);
+        }
+        warn!("Interrupted, finishing the records in flight, press Ctrl-C again to quit now");
+    })?;
+    if !cli.dry_run {
+        // downloads that were cut short by a previous run
+        match remove_partial_downloads(&cli.output_dir) {
+            Ok(0) => {}
+            Ok(removed) => info!("Removed {} partial downloads", removed),
+            Err(e) => warn!("Failed to remove partial downloads: {}", e),
+        }
+        if let Some(path) = &cli.sqlite {
+            database = Some(database::Database::open(path)?);
+        }
+    }
+
+    let mut summary = Summary::default();
+    // the fields of every dump, for the outputs covering the whole master file
+    let mut all_fields = Vec::new();
+    for (input, pe_name) in &dumps {
+        if INTERRUPTED.load(Ordering::Relaxed) {
             break;
         }
+        let dump = match open_dump(input) {
+            Ok(dump) => dump,
+            Err(e) if pe_dir => {
+                error!("Cannot open winbindex dump {}: {}", input.display(), e);
+                summary.failed += 1;
+                continue;
+            }
+            Err(e) => Cli::command()
+                .error(
+                    ErrorKind::Io,
+                    format!("cannot open winbindex dump {}: {}", input.display(), e),
+                )
+                .exit(),
+        };
+        let FieldList { symbols, fields } = match &cli.fields {
+            Some(path) => pdb_store::load_fields(path)?,
+            None => pdb_store::default_fields_for(pe_name),
+        };
+        let dump_source = DumpSource {
+            dump,
+            pe_name,
+            pe_dir,
+            symbols: &symbols,
+            fields: &fields,
+        };
+        process_dump(&cli, &client, &symbol_servers, dump_source, &mut master, &mut database, progress, &mut summary)?;
+        for field in fields {
+            if !all_fields.contains(&field) {
+                all_fields.push(field);
+            }
+        }
+    }
+    let duplicates = duplicate_versions(&master);
+    for group in &duplicates {
+        warn!(versions = group.join(",").as_str(); "{} have identical offsets and symbols, likely the same build", group.join(", "));
+    }
+    let bytes = DOWNLOADED_BYTES.load(Ordering::Relaxed);
+    let elapsed = started.elapsed();
+    info!(
+        records = summary.records, matched = summary.matched, existing = summary.existing,
+        downloaded = summary.downloaded, succeeded = summary.succeeded, not_indexed = summary.not_indexed,
+        failed = summary.failed, duplicates = duplicates.len(), bytes = bytes,
+        duration_ms = elapsed.as_millis() as u64;
+        "Summary: {} records, {} with a Windows version, {} downloaded, {} succeeded, {} not indexed, \
+         {} failed, {} groups of identical versions, {:.1} MiB in {:.1?}",
+        summary.records, summary.matched, summary.downloaded, summary.succeeded, summary.not_indexed,
+        summary.failed, duplicates.len(), bytes as f64 / (1024.0 * 1024.0), elapsed
+    );
+    if summary.download_times.count > 0 {
+        let (download_min_ms, download_avg_ms, download_max_ms) = summary.download_times.millis();
+        let (parse_min_ms, parse_avg_ms, parse_max_ms) = summary.parse_times.millis();
+        info!(
+            download_min_ms = download_min_ms, download_avg_ms = download_avg_ms, download_max_ms = download_max_ms,
+            parse_min_ms = parse_min_ms, parse_avg_ms = parse_avg_ms, parse_max_ms = parse_max_ms;
+            "Download min/avg/max {}/{}/{} ms over {} records, parse {}/{}/{} ms over {} records",
+            download_min_ms, download_avg_ms, download_max_ms, summary.download_times.count,
+            parse_min_ms, parse_avg_ms, parse_max_ms, summary.parse_times.count
+        );
+    }
+    if !summary.skipped.is_empty() {
+        let breakdown = summary
+            .skipped
+            .iter()
+            .map(|(reason, count)| format!("{} {}", count, reason.as_str()))
+            .join(", ");
+        info!(
+            unknown_version = summary.skipped(SkipReason::UnknownVersion),
+            out_of_range = summary.skipped(SkipReason::OutOfRange),
+            filtered = summary.skipped(SkipReason::Filtered),
+            limit_reached = summary.skipped(SkipReason::LimitReached),
+            duplicate = summary.skipped(SkipReason::Duplicate);
+            "Skipped: {}", breakdown
+        );
+    }
+    if cli.dry_run {
+        return Ok(());
+    }
+    let fields = all_fields;
+
+    if cli.incremental {
+        info!("{} new versions, {} skipped as already in {}", summary.succeeded, summary.existing, master_path.display());
+    } else if cli.resume {
+        info!("{} new versions, {} resumed from {}", summary.succeeded, summary.existing, cli.output_dir.display());
     }
 
+    fs::create_dir_all(&cli.output_dir)?;
+    write_master(&master_path, &master)?;
+
+    if cli.rust_module || cli.timeline || cli.changelog {
+        let versions = master
+            .iter()
+            .map(|(version, offsets)| Ok((version.clone(), pdb_store::PdbStore::from_json(offsets)?)))
+            .collect::<Result<Vec<_>, Box<dyn std::error::Error>>>()?;
+        if cli.rust_module {
+            let mut file = File::create(cli.output_dir.join("offsets.rs"))?;
+            codegen::write_rust_consts(&versions, &fields, &mut file)?;
+        }
+        // the update each version first shipped in, to tell which KB moved an offset
+        let updates: HashMap<_, _> = master
+            .iter()
+            .filter_map(|(version, offsets)| Some((version.clone(), offsets["updates"][0]["kb"].as_str()?.to_string())))
+            .collect();
+        if cli.timeline {
+            let mut file = File::create(cli.output_dir.join("timeline.csv"))?;
+            codegen::write_timeline_csv(&versions, &updates, &fields, &mut file)?;
+            let mut file = File::create(cli.output_dir.join("timeline.md"))?;
+            codegen::write_timeline_markdown(&versions, &updates, &fields, &mut file)?;
+        }
+        if cli.changelog {
+            let codenames: HashMap<_, _> = master
+                .iter()
+                .filter_map(|(version, offsets)| Some((version.clone(), offsets["codename"].as_str()?.to_string())))
+                .collect();
+            let mut file = io::BufWriter::new(File::create(cli.output_dir.join("changelog.md"))?);
+            codegen::write_changelog(&versions, &codenames, &updates, &fields, &mut file)?;
+            file.flush()?;
+        }
+    }
+
+    if summary.out_of_space {
+        error!("Stopped for lack of disk space, the results so far are saved");
+        std::process::exit(1);
+    }
+    if INTERRUPTED.load(Ordering::Relaxed) {
+        warn!("Interrupted, the results so far are saved");
+        std::process::exit(130);
+    }
+    if summary.failed > 0 && !cli.keep_going {
+        std::process::exit(1);
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lpus::utils::version::parse_version;
+
+    const TEST_DUMP: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/test.json");
+
+    fn selection() -> Selection {
+        Selection {
+            limit: usize::MAX,
+            skip_unknown: false,
+            min_version: None,
+            max_version: None,
+            versions: Vec::new(),
+            since: None,
+            codenames: Vec::new(),
+            pe_dir: None,
+            existing: HashSet::new(),
+            verbose: false,
+        }
+    }
+
+    fn select(selection: &Selection) -> (Summary, usize) {
+        let mut selected = 0;
+        let dump = File::open(TEST_DUMP).unwrap();
+        let summary = select_records(dump, selection, |_, _, _| {
+            selected += 1;
+            ControlFlow::Continue(())
+        })
+        .unwrap();
+        (summary, selected)
+    }
+
+    #[test]
+    fn records_out_of_range_still_count_as_matched() {
+        let (summary, selected) = select(&selection());
+        assert_eq!((summary.records, summary.matched, selected), (1, 1, 1));
+        assert!(summary.skipped.is_empty());
+
+        // test.json holds 10.0.26100.6725
+        let newer = Selection { min_version: parse_version("10.0.26100.7000"), ..selection() };
+        let (summary, selected) = select(&newer);
+        assert_eq!((summary.records, summary.matched, selected), (1, 1, 0));
+        assert_eq!(summary.skipped(SkipReason::OutOfRange), 1);
+        assert_eq!(summary.skipped(SkipReason::Filtered), 0);
+
+        let older = Selection { max_version: parse_version("10.0.22621.0"), ..selection() };
+        let (summary, selected) = select(&older);
+        assert_eq!((summary.records, summary.matched, selected), (1, 1, 0));
+
+        let unlisted = Selection { versions: vec![parse_version_prefix("10.0.22621").unwrap()], ..selection() };
+        let (summary, selected) = select(&unlisted);
+        assert_eq!((summary.records, summary.matched, selected), (1, 1, 0));
+
+        let other_release = Selection { codenames: vec!["22h2".to_string()], ..selection() };
+        let (summary, selected) = select(&other_release);
+        assert_eq!((summary.matched, selected), (1, 0));
+        assert_eq!(summary.skipped(SkipReason::Filtered), 1);
+    }
+
+    #[test]
+    fn master_files_round_trip_compressed_or_not() {
+        let dir = std::env::temp_dir().join(format!("lpus-master-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut master = serde_json::Map::new();
+        master.insert(
+            "10.0.26100.6725/x64".to_string(),
+            serde_json::json!({
+                "schema_version": SCHEMA_VERSION,
+                "symbols": {"PsLoadedModuleList": 0xcfc430},
+                "structs": {
+                    "_EPROCESS": {"Token": {"offset": 0x248, "type": "_EX_FAST_REF", "size": 8, "pointer": false}},
+                },
+                "sizes": {"_EPROCESS": 0xb80},
+            }),
+        );
+
+        for (compress, magic) in [
+            (None, &b"{"[..]),
+            (Some(Compression::Gzip), &[0x1f, 0x8b][..]),
+            (Some(Compression::Zstd), &[0x28, 0xb5, 0x2f, 0xfd][..]),
+        ] {
+            let path = master_file(&dir, compress);
+            write_master(&path, &master).unwrap();
+            assert!(fs::read(&path).unwrap().starts_with(magic), "{}", path.display());
+            assert_eq!(load_master(&path).unwrap(), master, "{}", path.display());
+        }
+        assert_eq!(load_master(&dir.join("missing.json")).unwrap(), serde_json::Map::new());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
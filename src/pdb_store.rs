@@ -1,34 +1,170 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::fs::File;
 use std::io;
-use std::io::Read;
 use std::path::PathBuf;
 use std::path::Path;
 use std::io::Write;
 
 use itertools::Itertools;
+use log::{debug, warn};
 
 // use app_dirs::{app_dir, AppDataType};
 use pdb::{
-    ClassType, FallibleIterator, ModifierType, Rva, SymbolData, TypeData, TypeFinder, TypeIndex,
-    PDB,
+    ClassType, EnumerationType, FallibleIterator, Indirection, ModifierType, Rva, SymbolData, TypeData, TypeFinder,
+    TypeIndex, UnionType, Variant, PDB,
 };
 
 use crate::address::Address;
+use crate::download::{check_free_space, fetch_file, write_atomically};
+use crate::error;
+use crate::format::{Member, Offsets};
+use crate::pe::{CodeView, PeFile};
 use crate::utils::mask_cast::*;
 
-const KERNEL_PDB_NAME: &str = "ntkrnlmp.pdb";
-const PDB_SERVER_PATH: &str = "http://msdl.microsoft.com/download/symbols";
+pub const PDB_SERVER_PATH: &str = "https://msdl.microsoft.com/download/symbols";
+
+// The kernels we extract are x64 or arm64, both with 8 byte pointers
+const POINTER_SIZE: u64 = 8;
 
 type BoxResult<T> = Result<T, Box<dyn Error>>;
 
 type SymbolStore = HashMap<String, u64>;
 type StructStore = HashMap<String, HashMap<String, (String, u64)>>;
+// enum name -> (underlying primitive type, constant -> value)
+type EnumStore = HashMap<String, (String, HashMap<String, i64>)>;
+// The mask handler returned by decompose, see utils::mask_cast
+type MaskHandler = Box<dyn Fn(u64) -> u64>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemberLocation {
+    Byte(u32),
+    // byte is the offset of the storage unit holding the bits
+    Bitfield { byte: u32, bit: u8, len: u8 },
+}
+
+impl MemberLocation {
+    fn from_member(memtype: &str, offset: u32) -> Self {
+        // ":" is my own sperator for bitfield type, <type>:<pos>:<len>
+        let mut parts = memtype.rsplitn(3, ':');
+        let len = parts.next().and_then(|len| len.parse().ok());
+        let bit = parts.next().and_then(|bit| bit.parse().ok());
+        match (bit, len, parts.next()) {
+            (Some(bit), Some(len), Some(_)) => MemberLocation::Bitfield { byte: offset, bit, len },
+            _ => MemberLocation::Byte(offset),
+        }
+    }
+
+    pub fn byte(&self) -> u32 {
+        match *self {
+            MemberLocation::Byte(byte) => byte,
+            MemberLocation::Bitfield { byte, .. } => byte,
+        }
+    }
+
+    fn shift(self, base: u32) -> Option<Self> {
+        Some(match self {
+            MemberLocation::Byte(byte) => MemberLocation::Byte(base.checked_add(byte)?),
+            MemberLocation::Bitfield { byte, bit, len } => MemberLocation::Bitfield {
+                byte: base.checked_add(byte)?,
+                bit,
+                len,
+            },
+        })
+    }
+}
+
+/// A member resolved by get_field, where it is and what it holds.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FieldInfo {
+    pub location: MemberLocation,
+    // the member type as kept in the store, e.g. _EX_PUSH_LOCK, Void*, U32:0:1
+    pub type_name: String,
+    // for bitfields the size of the storage unit
+    pub size: Option<u64>,
+    pub is_pointer: bool,
+}
+
+// A member type string of the store taken apart, e.g. U32, _LIST_ENTRY*, U8[16]
+// or U32:0:1 for bitfields. Modifiers like const are dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemberType<'a> {
+    Bitfield { base: &'a str, bit: u64, len: u64 },
+    Pointer(&'a str),
+    // pdb gives the length of an array in bytes, not elements
+    Array { element: &'a str, bytes: u64 },
+    Function(&'a str),
+    // a primitive kind (U32, UChar, ...) or a struct name
+    Named(&'a str),
+}
 
+impl<'a> MemberType<'a> {
+    pub fn parse(mut memtype: &'a str) -> Self {
+        for modifier in ["const ", "volatile ", "unaligned ", "modifier ", "static "] {
+            memtype = memtype.strip_prefix(modifier).unwrap_or(memtype);
+        }
+
+        let mut parts = memtype.rsplitn(3, ':');
+        let len = parts.next().and_then(|len| len.parse().ok());
+        let bit = parts.next().and_then(|bit| bit.parse().ok());
+        if let (Some(bit), Some(len), Some(base)) = (bit, len, parts.next()) {
+            return MemberType::Bitfield { base, bit, len };
+        }
+        if let Some(pointee) = memtype.strip_suffix('*') {
+            return MemberType::Pointer(pointee);
+        }
+        // U8[16][64] is an array of 64 bytes of U8[16], the last dimension is the outermost
+        if let Some((element, bytes)) = memtype.strip_suffix(']').and_then(|rest| rest.rsplit_once('['))
+            && let Ok(bytes) = bytes.parse()
+        {
+            return MemberType::Array { element, bytes };
+        }
+        if memtype.contains('(') {
+            return MemberType::Function(memtype);
+        }
+        MemberType::Named(memtype)
+    }
+}
+
+fn primitive_size(kind: &str) -> Option<u64> {
+    match kind {
+        "Void" => Some(0),
+        "Char" | "RChar" | "UChar" | "I8" | "U8" | "Bool8" => Some(1),
+        "WChar" | "RChar16" | "I16" | "U16" | "Bool16" => Some(2),
+        "RChar32" | "I32" | "U32" | "F32" | "Bool32" | "HRESULT" => Some(4),
+        "I64" | "U64" | "F64" | "Bool64" => Some(8),
+        "I128" | "U128" => Some(16),
+        _ => None,
+    }
+}
+
+fn windbg_primitive(kind: &str) -> &str {
+    // the names dt uses for primitives
+    match kind {
+        "Void" => "Void",
+        "Char" | "RChar" | "I8" => "Char",
+        "UChar" | "U8" => "UChar",
+        "WChar" | "RChar16" => "Wchar",
+        "I16" => "Int2B",
+        "U16" => "Uint2B",
+        "I32" | "HRESULT" => "Int4B",
+        "U32" | "RChar32" => "Uint4B",
+        "I64" => "Int8B",
+        "U64" => "Uint8B",
+        "F32" => "Float",
+        "F64" => "Float8",
+        "Bool8" => "Bool",
+        other => other,
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
 pub struct PdbStore {
     pub symbols: SymbolStore,
     pub structs: StructStore,
+    // the names in structs that are unions
+    pub unions: HashSet<String>,
+    pub enums: EnumStore,
 }
 
 impl PdbStore {
@@ -40,19 +176,94 @@ impl PdbStore {
     pub fn get_offset(&self, name: &str) -> Option<u64> {
         if name.contains(".") {
             let v: Vec<&str> = name.split_terminator('.').collect();
-            match self.structs.get(v[0]) {
-                Some(member_info) => match member_info.get(v[1]) {
-                    Some((_memtype, offset)) => Some(*offset),
-                    None => None,
-                },
-                None => None,
-            }
+            self.structs
+                .get(v[0])
+                .and_then(|member_info| member_info.get(v[1]))
+                .map(|(_memtype, offset)| *offset)
         } else {
-            match self.symbols.get(name) {
-                Some(offset) => Some(*offset),
-                None => None,
+            self.symbols.get(name).copied()
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn get_symbol_rva(&self, name: &str) -> Option<u32> {
+        // RVA of a global symbol, e.g. PsInitialSystemProcess
+        self.symbols
+            .get(name)
+            .and_then(|rva| u32::try_from(*rva).ok())
+    }
+
+    #[allow(dead_code)]
+    pub fn get_field_offset(&self, struct_name: &str, field_name: &str) -> Option<MemberLocation> {
+        // Location of a member from the start of struct_name, with the bit position for bitfields.
+        // A dotted field_name is the absolute offset through the embedded structs
+        self.get_field(struct_name, field_name).map(|field| field.location)
+    }
+
+    pub fn get_field(&self, struct_name: &str, field_name: &str) -> Option<FieldInfo> {
+        // field_name can walk through embedded structs, e.g. _KPROCESS Header.Type
+        let (member, next) = match field_name.split_once('.') {
+            Some((member, next)) => (member, Some(next)),
+            None => (field_name, None),
+        };
+        let (memtype, offset) = self.structs.get(struct_name)?.get(member)?;
+        let offset = u32::try_from(*offset).ok()?;
+        match next {
+            None => Some(FieldInfo {
+                location: MemberLocation::from_member(memtype, offset),
+                type_name: memtype.clone(),
+                size: self.member_size(memtype),
+                is_pointer: matches!(MemberType::parse(memtype), MemberType::Pointer(_)),
+            }),
+            Some(next) => match MemberType::parse(memtype) {
+                // members of an anonymous union/struct are already flattened into this
+                // struct at their absolute offset
+                MemberType::Named(name) if is_anonymous(name) => self.get_field(struct_name, next),
+                // an embedded struct, e.g. _EPROCESS Pcb.ThreadListHead, offsets add up
+                // along the path
+                MemberType::Named(name) => {
+                    let mut field = self.get_field(name, next)?;
+                    field.location = field.location.shift(offset)?;
+                    Some(field)
+                }
+                // a pointer member is not part of the struct, we cannot walk through it,
+                // neither through arrays or bitfields
+                _ => None,
+            },
+        }
+    }
+
+    /// The struct named like name ignoring case, for lookups typed by hand.
+    /// An exact match wins, names that only differ in case are an error listing them.
+    pub fn struct_name_ignore_case<'s>(&'s self, name: &'s str) -> BoxResult<&'s str> {
+        match_ignore_case(self.structs.keys(), name).map_err(|e| format!("struct {}", e).into())
+    }
+
+    /// The symbol named like name ignoring case, see struct_name_ignore_case.
+    pub fn symbol_name_ignore_case<'s>(&'s self, name: &'s str) -> BoxResult<&'s str> {
+        match_ignore_case(self.symbols.keys(), name).map_err(|e| format!("symbol {}", e).into())
+    }
+
+    /// struct_name and the path of field_name through embedded structs as the pdb
+    /// spells them, each part matched ignoring case, e.g. _eprocess pcb.directorytablebase
+    /// -> _EPROCESS Pcb.DirectoryTableBase.
+    pub fn field_name_ignore_case(&self, struct_name: &str, field_name: &str) -> BoxResult<(String, String)> {
+        let struct_name = self.struct_name_ignore_case(struct_name)?;
+        let mut path = Vec::new();
+        let mut current = struct_name;
+        for part in field_name.split('.') {
+            let member_info = self.structs.get(current).ok_or(format!("no struct named {}", current))?;
+            let member = match_ignore_case(member_info.keys(), part)
+                .map_err(|e| format!("member of {} {}", current, e))?;
+            // walk into embedded structs, anonymous ones are flattened into this one
+            if let MemberType::Named(name) = MemberType::parse(&member_info[member].0)
+                && !is_anonymous(name)
+            {
+                current = name;
             }
+            path.push(member);
         }
+        Ok((struct_name.to_string(), path.join(".")))
     }
 
     #[allow(dead_code)]
@@ -66,7 +277,7 @@ impl PdbStore {
         match self.structs.get(name_part[0]) {
             Some(member_info) => match member_info.get(name_part[1]) {
                 Some((memtype, offset)) => {
-                    if next.len() != 0 {
+                    if !next.is_empty() {
                         if memtype.contains("*") {
                             return Err(format!(
                                 "Cannot dereference pointer at {} {}",
@@ -86,7 +297,7 @@ impl PdbStore {
         }
     }
 
-    pub fn decompose(&self, source: &Address, full_name: &str) -> BoxResult<(Address, Box<dyn Fn(u64) -> u64>, u64)> {
+    pub fn decompose(&self, source: &Address, full_name: &str) -> BoxResult<(Address, MaskHandler, u64)> {
         // Get the Address object for a field inside a struct
         // If the field is a bit field:
         //  - The second value in the return tuple is a mask to get the exact bit(s)
@@ -106,7 +317,7 @@ impl PdbStore {
             .get(name_part[1])
             .ok_or(format!("No member {} in {}", name_part[1], name_part[0]))?;
 
-        if next.len() == 0 {
+        if next.is_empty() {
             // Default mask, getting every bits.
             let mut mask_handler = get_bit_mask_handler(0, 64);
             let mut required_len = 0;
@@ -135,117 +346,179 @@ impl PdbStore {
     }
 
     #[allow(dead_code)]
-    pub fn print_default_information<W: Write>(&self, stream: &mut W) {
-        let need_symbols = [
-            "PsLoadedModuleList",
-            "PsActiveProcessHead",
-            "KeNumberNodes",
-            "PoolBigPageTable",
-            "PoolBigPageTableSize",
-            // "PoolVector", "ExpNumberOfNonPagedPools",
-            "KdDebuggerDataBlock",
-            "MmNonPagedPoolStart",
-            "MmNonPagedPoolEnd", // Windows XP
-            "MiNonPagedPoolStartAligned",
-            "MiNonPagedPoolEnd",
-            "MiNonPagedPoolBitMap", // Windows 7, 8
-            "MiNonPagedPoolBitMap",
-            "MiNonPagedPoolVaBitMap",
-            "MiState", // Windows 10
-        ];
-
-        let mut need_structs = HashMap::new();
-        need_structs.insert(
-            "_POOL_HEADER",
-            vec!["struct_size", "PoolType", "BlockSize", "PoolTag"],
-        );
-        need_structs.insert("_PEB", vec![]);
-        need_structs.insert("_LIST_ENTRY", vec!["Flink", "Blink"]);
-        need_structs.insert("_FILE_OBJECT", vec!["FileName"]);
-        need_structs.insert(
-            "_EPROCESS",
-            vec![
-                "struct_size",
-                "UniqueProcessId",
-                "ActiveProcessLinks",
-                "CreateTime",
-                "Peb",
-                "ImageFilePointer",
-                "ImageFileName",
-                "ThreadListHead",
-            ],
-        );
-        need_structs.insert(
-            "_KDDEBUGGER_DATA64",
-            vec![
-                "MmNonPagedPoolStart",
-                "MmNonPagedPoolEnd", // Windows XP
-            ],
-        );
-        need_structs.insert("_POOL_TRACKER_BIG_PAGES", vec![]);
-
-        // these struct supports finding NonPagedPool{First,Last}Va in windows 10
-        need_structs.insert(
-            "_MI_SYSTEM_INFORMATION",
-            vec![
-                "Hardware",              // windows 10 2016+
-                "SystemNodeInformation", // windows 10 2015
-            ],
-        );
-        need_structs.insert(
-            "_MI_HARDWARE_STATE",
-            vec![
-                "SystemNodeInformation",  // till windows 10 1900
-                "SystemNodeNonPagedPool", // windows insider, 2020
-            ],
-        );
-        need_structs.insert(
-            "_MI_SYSTEM_NODE_INFORMATION",
-            vec![
-                // till windows 10 1900
-                "NonPagedPoolFirstVa",
-                "NonPagedPoolLastVa",
-                "NonPagedBitMap",            // missing on windows 10 1900+
-                "DynamicBitMapNonPagedPool", // some weird field
-            ],
-        );
-        need_structs.insert(
-            "_MI_SYSTEM_NODE_NONPAGED_POOL",
-            vec![
-                // windows insider, 2020
-                "NonPagedPoolFirstVa",
-                "NonPagedPoolLastVa",
-                "DynamicBitMapNonPagedPool", // some weird field
-            ],
-        );
-        need_structs.insert("_MI_DYNAMIC_BITMAP", vec![]);
-        need_structs.insert("_RTL_BITMAP", vec![]); // windows 10 until 2020
-        need_structs.insert("_RTL_BITMAP_EX", vec![]); // windows insider, 2020
-
-        for &symbol in &need_symbols {
-            match self.symbols.get(symbol) {
-                Some(offset) => {
-                    writeln!(stream, "0x{:x} {}", offset, symbol);
-                },
-                None => ()
+    pub fn print_default_information<W: Write>(&self, stream: &mut W) -> io::Result<()> {
+        let FieldList { symbols, fields } = default_fields();
+        self.print_information(&symbols, &fields, stream)
+    }
+
+    /// The given symbols followed by the given fields, fields that cannot be
+    /// resolved are reported and left out.
+    pub fn print_information<W: Write>(
+        &self,
+        symbols: &[String],
+        fields: &[(String, String)],
+        stream: &mut W,
+    ) -> io::Result<()> {
+        for symbol in symbols {
+            if let Some(offset) = self.symbols.get(symbol) {
+                writeln!(stream, "0x{:x} {}", offset, symbol)?;
             }
         }
 
-        for (&struct_name, members) in need_structs.iter().sorted() {
-            match self.structs.get(struct_name) {
-                Some(member_info) => {
-                    for &member in members {
-                        match member_info.get(member) {
-                            Some((memtype, offset)) => {
-                                writeln!(stream, "0x{:x} {} {}.{}", offset, memtype, struct_name, member);
-                            }
-                            None => ()
-                        }
-                    }
+        for (struct_name, member) in fields {
+            let direct = self.structs.get(struct_name).and_then(|member_info| member_info.get(member));
+            match (direct, self.get_field_offset(struct_name, member)) {
+                (Some((memtype, offset)), _) => {
+                    writeln!(stream, "0x{:x} {} {}.{}", offset, memtype, struct_name, member)?;
                 }
-                None => ()
+                // a path through nested structs, e.g. _EPROCESS.Pcb.DirectoryTableBase
+                (None, Some(location)) => {
+                    writeln!(stream, "0x{:x} {}.{}", location.byte(), struct_name, member)?;
+                }
+                (None, None) => warn!("{}.{} not found", struct_name, member),
             }
         }
+        Ok(())
+    }
+
+    pub fn to_offsets(&self) -> Offsets {
+        // struct_size is kept with the members in the store, it goes to sizes
+        let structs = self
+            .structs
+            .iter()
+            .map(|(struct_name, member_info)| {
+                let members = member_info
+                    .iter()
+                    .filter(|(member, _)| *member != "struct_size")
+                    .map(|(member, (memtype, offset))| {
+                        let info = Member {
+                            offset: *offset,
+                            type_name: memtype.clone(),
+                            size: self.member_size(memtype),
+                            pointer: matches!(MemberType::parse(memtype), MemberType::Pointer(_)),
+                        };
+                        (member.clone(), info)
+                    })
+                    .collect();
+                (struct_name.clone(), members)
+            })
+            .collect();
+        let sizes = self
+            .structs
+            .keys()
+            .filter_map(|struct_name| Some((struct_name.clone(), self.struct_size(struct_name)?)))
+            .collect();
+        let symbols = self.symbols.iter().map(|(name, rva)| (name.clone(), *rva)).collect();
+        Offsets { symbols, structs, sizes }
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        // { "symbols": { name: rva },
+        //   "structs": { struct: { member: { "offset", "type", "size", "pointer" } } },
+        //   "sizes": { struct: size } }
+        serde_json::to_value(self.to_offsets()).expect("offsets serialize to JSON")
+    }
+
+    pub fn from_json(value: &serde_json::Value) -> BoxResult<PdbStore> {
+        // Read back what to_json wrote, older files have a bare offset for each member
+        // and no type
+        let mut symbols = SymbolStore::new();
+        for (name, rva) in value["symbols"].as_object().ok_or("No symbols in json")? {
+            symbols.insert(name.clone(), rva.as_u64().ok_or(format!("Bad rva for {}", name))?);
+        }
+
+        let mut structs = StructStore::new();
+        for (struct_name, members) in value["structs"].as_object().ok_or("No structs in json")? {
+            let mut member_info = HashMap::new();
+            for (member, info) in members.as_object().ok_or(format!("Bad struct {}", struct_name))? {
+                let (offset, memtype) = match info {
+                    serde_json::Value::Object(_) => (&info["offset"], info["type"].as_str().unwrap_or_default()),
+                    offset => (offset, ""),
+                };
+                let offset = offset
+                    .as_u64()
+                    .ok_or(format!("Bad offset for {}.{}", struct_name, member))?;
+                member_info.insert(member.clone(), (memtype.to_string(), offset));
+            }
+            structs.insert(struct_name.clone(), member_info);
+        }
+        // struct sizes are only in files written since they were recorded
+        if let Some(sizes) = value["sizes"].as_object() {
+            for (struct_name, size) in sizes {
+                let size = size.as_u64().ok_or(format!("Bad size for {}", struct_name))?;
+                structs
+                    .entry(struct_name.clone())
+                    .or_default()
+                    .insert("struct_size".to_string(), ("U32".to_string(), size));
+            }
+        }
+
+        Ok(PdbStore {
+            symbols,
+            structs,
+            unions: HashSet::new(),
+            enums: HashMap::new(),
+        })
+    }
+
+    /// Total size in bytes of a struct, including padding.
+    pub fn struct_size(&self, struct_name: &str) -> Option<u64> {
+        self.structs.get(struct_name)?.get("struct_size").map(|(_, size)| *size)
+    }
+
+    /// Size in bytes of a member type, None for types the store does not know.
+    pub fn type_size(&self, memtype: &str, pointer_size: u64) -> Option<u64> {
+        match MemberType::parse(memtype) {
+            MemberType::Pointer(_) => Some(pointer_size),
+            MemberType::Array { bytes, .. } => Some(bytes),
+            MemberType::Named(name) => primitive_size(name)
+                .or_else(|| self.struct_size(name))
+                .or_else(|| self.enums.get(name).and_then(|(base, _)| primitive_size(base))),
+            MemberType::Bitfield { .. } | MemberType::Function(_) => None,
+        }
+    }
+
+    fn member_size(&self, memtype: &str) -> Option<u64> {
+        // a bitfield takes the size of the unit it is packed in
+        match MemberType::parse(memtype) {
+            MemberType::Bitfield { base, .. } => self.type_size(base, POINTER_SIZE),
+            _ => self.type_size(memtype, POINTER_SIZE),
+        }
+    }
+
+    fn windbg_type(&self, memtype: &str) -> String {
+        match MemberType::parse(memtype) {
+            MemberType::Bitfield { bit, len: 1, .. } => format!("Pos {}, 1 Bit", bit),
+            MemberType::Bitfield { bit, len, .. } => format!("Pos {}, {} Bits", bit, len),
+            MemberType::Pointer(pointee) => format!("Ptr64 {}", self.windbg_type(pointee)),
+            MemberType::Array { element, bytes } => match self.type_size(element, 8) {
+                Some(size) if size > 0 => format!("[{}] {}", bytes / size, self.windbg_type(element)),
+                _ => format!("[0x{:x} bytes] {}", bytes, self.windbg_type(element)),
+            },
+            MemberType::Function(function) => function.to_string(),
+            MemberType::Named(name) => windbg_primitive(name).to_string(),
+        }
+    }
+
+    /// Layout of a struct the way WinDbg's `dt` shows it, one `+0xNNN Member : Type`
+    /// line per member sorted by offset. Nested structs are shown by name,
+    /// pointers are assumed to be 64 bit.
+    pub fn dump_struct(&self, name: &str, out: &mut impl Write) -> io::Result<()> {
+        let member_info = self.structs.get(name).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, format!("no struct named {}", name))
+        })?;
+        let members: Vec<(&String, &(String, u64))> = member_info
+            .iter()
+            .filter(|(member, _)| *member != "struct_size")
+            .sorted_by(|(m1, (_, o1)), (m2, (_, o2))| o1.cmp(o2).then(m1.cmp(m2)))
+            .collect();
+        let width = members.iter().map(|(member, _)| member.len()).max().unwrap_or(0);
+
+        writeln!(out, "{}", name)?;
+        for (member, (memtype, offset)) in members {
+            writeln!(out, "   +0x{:03x} {:<width$} : {}", offset, member, self.windbg_type(memtype), width = width)?;
+        }
+        Ok(())
     }
 
     pub fn dt(&self, struct_name: &str) -> BoxResult<()> {
@@ -275,10 +548,80 @@ impl PdbStore {
     }
 }
 
+/// The symbols and struct members written to info.txt
+#[derive(Debug, PartialEq, Eq)]
+pub struct FieldList {
+    pub symbols: Vec<String>,
+    pub fields: Vec<(String, String)>,
+}
+
+// Default symbols and fields per PE, in the --fields format
+const NTOSKRNL_FIELDS: &str = include_str!("fields/ntoskrnl.txt");
+const WIN32K_FIELDS: &str = include_str!("fields/win32k.txt");
+const CI_FIELDS: &str = include_str!("fields/ci.txt");
+
+/// Read a list of fields to extract, one Struct.Field or symbol name per line.
+/// Blank lines and lines starting with # are ignored.
+pub fn load_fields(path: &Path) -> BoxResult<FieldList> {
+    let content = std::fs::read_to_string(path)?;
+    parse_fields(&content, &path.display().to_string())
+}
+
+fn parse_fields(content: &str, source: &str) -> BoxResult<FieldList> {
+    let mut symbols = Vec::new();
+    let mut fields = Vec::new();
+    for (number, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        // only the first dot splits, the rest is a path into nested structs
+        match line.split_once('.') {
+            Some((struct_name, member)) if !struct_name.is_empty() && !member.is_empty() => {
+                fields.push((struct_name.to_string(), member.to_string()));
+            }
+            None if !line.contains(char::is_whitespace) => symbols.push(line.to_string()),
+            _ => {
+                return Err(
+                    format!("{}:{}: expected Struct.Field or a symbol, got {:?}", source, number + 1, line).into(),
+                );
+            }
+        }
+    }
+    Ok(FieldList { symbols, fields })
+}
+
+pub fn default_fields() -> FieldList {
+    default_fields_for("ntoskrnl.exe")
+}
+
+/// The default symbols and fields for a PE from the lists embedded in the binary,
+/// the win32k ones for the win32k drivers, the ci.dll ones for ci.dll and the
+/// kernel ones for anything else.
+pub fn default_fields_for(pe_name: &str) -> FieldList {
+    let pe_name = pe_name.to_ascii_lowercase();
+    let (source, content) = if pe_name.starts_with("win32k") {
+        ("win32k.txt", WIN32K_FIELDS)
+    } else if pe_name == "ci.dll" {
+        ("ci.txt", CI_FIELDS)
+    } else {
+        ("ntoskrnl.txt", NTOSKRNL_FIELDS)
+    };
+    parse_fields(content, source).expect("embedded field lists are well formed")
+}
+
 fn get_type_as_str(type_finder: &TypeFinder, typ: &TypeIndex) -> String {
-    match type_finder.find(*typ).unwrap().parse().unwrap() {
-        TypeData::Class(ct) => format!("{}", ct.name.to_string()),
-        TypeData::Primitive(pt) => format!("{:?}", pt.kind),
+    // the pdb crate does not parse every C++ leaf found in the win32k pdbs
+    let Ok(data) = type_finder.find(*typ).and_then(|t| t.parse()) else {
+        return "UNKNOWN".to_string();
+    };
+    match data {
+        TypeData::Class(ct) => ct.name.to_string().into_owned(),
+        // PVOID and friends are primitives with an indirection, not pointer types
+        TypeData::Primitive(pt) => match pt.indirection {
+            Indirection::None => format!("{:?}", pt.kind),
+            _ => format!("{:?}*", pt.kind),
+        },
         TypeData::Pointer(pt) => format!("{}*", get_type_as_str(type_finder, &pt.underlying_type)),
         TypeData::StaticMember(st) => {
             format!("static {}", get_type_as_str(type_finder, &st.field_type))
@@ -400,7 +743,7 @@ fn get_type_as_str(type_finder: &TypeFinder, typ: &TypeIndex) -> String {
         TypeData::Bitfield(bft) => {
             format!("{}:{}:{}", get_type_as_str(type_finder, &bft.underlying_type), bft.position, bft.length)
         },
-        TypeData::FieldList(_flt) => format!("fieldlist"),
+        TypeData::FieldList(_flt) => "fieldlist".to_string(),
         // TypeData::ArgumentList(alt) => {
         //     format!("arglist")
         // },
@@ -408,153 +751,273 @@ fn get_type_as_str(type_finder: &TypeFinder, typ: &TypeIndex) -> String {
         //     format!("methodlist")
         // },
         unk => match unk.name() {
-            Some(s) => format!("{}", s.to_string()),
+            Some(s) => s.to_string().into_owned(),
             _ => "UNNOWN".to_string(),
         },
     }
 }
 
-fn get_guid_age(exe_file: &Path) -> BoxResult<(String, u32)> {
-    // TODO: Check file existance
-    let mut file = File::open(exe_file)?;
-
-    let mut buffer = Vec::new();
-    file.read_to_end(&mut buffer)?;
-
-    let mut buffiter = buffer.chunks(4);
-    while buffiter.next().unwrap() != [0x52, 0x53, 0x44, 0x53] {
-        // signature == RSDS
-    }
-
-    // next 16 bytes is guid in raw bytes
-    let raw_guid: Vec<u8> = vec![
-        buffiter.next().unwrap(),
-        buffiter.next().unwrap(),
-        buffiter.next().unwrap(),
-        buffiter.next().unwrap(),
-    ]
-    .concat();
-
-    // guid to hex string
-    let guid = (vec![
-        raw_guid[3],
-        raw_guid[2],
-        raw_guid[1],
-        raw_guid[0],
-        raw_guid[5],
-        raw_guid[4],
-        raw_guid[7],
-        raw_guid[6],
-        raw_guid[8],
-        raw_guid[9],
-        raw_guid[10],
-        raw_guid[11],
-        raw_guid[12],
-        raw_guid[13],
-        raw_guid[14],
-        raw_guid[15],
-    ]
-    .iter()
-    .map(|b| format!("{:02X}", b))
-    .collect::<Vec<String>>())
-    .join("");
-
-    // next 4 bytes is age, in little endian
-    let raw_age = buffiter.next().unwrap();
-    let age = u32::from_le_bytes([raw_age[0], raw_age[1], raw_age[2], raw_age[3]]);
-
-    Ok((guid, age))
-}
-
-fn download_pdb(pdbname: &str, guid: &str, age: u32, outfile: &PathBuf) -> BoxResult<()> {
-    let downloadurl = format!(
-        "{}/{}/{}{:X}/{}",
-        PDB_SERVER_PATH, pdbname, guid, age, pdbname
-    );
-    println!("{}", downloadurl);
+// The one of names equal to name ignoring ASCII case, or name itself when it is there
+fn match_ignore_case<'s>(names: impl Iterator<Item = &'s String>, name: &'s str) -> Result<&'s str, String> {
+    let matches: Vec<&str> = names
+        .filter(|candidate| candidate.eq_ignore_ascii_case(name))
+        .map(String::as_str)
+        .sorted()
+        .collect();
+    match matches.as_slice() {
+        [] => Err(format!("{} not found", name)),
+        [found] => Ok(found),
+        _ if matches.contains(&name) => Ok(name),
+        _ => Err(format!("{} is ambiguous: {}", name, matches.join(", "))),
+    }
+}
 
-    let mut resp = reqwest::blocking::get(&downloadurl)?;
-    let mut out = File::create(outfile)?;
-    io::copy(&mut resp, &mut out)?;
-    Ok(())
+fn is_anonymous(name: &str) -> bool {
+    name.starts_with("<unnamed-") || name.starts_with("<anonymous-") || name.starts_with("__unnamed")
 }
 
-pub fn parse_pdb(ntoskrnl_path: &Path) -> BoxResult<PdbStore> {
-    // TODO: Resolve pdb name
-    // ntoskrnl.exe -> ntkrnlmp.pdb
-    // tcpip.sys -> tcpip.pdb ?????
-    // There may be more pdb files in the future
-    let (guid, age) = get_guid_age(ntoskrnl_path)?;
-    let mut pdb_path = ntoskrnl_path.to_path_buf().clone();
-    pdb_path.set_file_name(KERNEL_PDB_NAME);
-
-    download_pdb(KERNEL_PDB_NAME, &guid, age, &pdb_path)?;
-
-    let f = File::open(pdb_path.as_path())?;
-    let mut pdb = PDB::open(f)?;
-
-    let info = pdb.pdb_information()?;
-    let dbi = pdb.debug_information()?;
-    println!(
-        "PDB for {}, guid: {}, age: {}\n",
-        dbi.machine_type().unwrap(),
-        info.guid,
-        dbi.age().unwrap_or(0)
-    );
+fn anonymous_fields(type_finder: &TypeFinder, typ: TypeIndex) -> Option<TypeIndex> {
+    match type_finder.find(typ).ok()?.parse().ok()? {
+        TypeData::Class(ClassType {
+            name,
+            fields: Some(fields),
+            ..
+        }) if is_anonymous(&name.to_string()) => Some(fields),
+        TypeData::Union(UnionType { name, fields, .. }) if is_anonymous(&name.to_string()) => {
+            Some(fields)
+        }
+        _ => None,
+    }
+}
 
-    let type_information = pdb.type_information()?;
-    let mut type_finder = type_information.type_finder();
-    let mut iter = type_information.iter();
-    while let Some(_typ) = iter.next().unwrap() {
-        type_finder.update(&iter);
+// Field lists of the complete classes by name, base classes are often referenced
+// through a forward declaration without fields
+type ClassFields = HashMap<String, TypeIndex>;
+
+fn base_class_fields(type_finder: &TypeFinder, classes: &ClassFields, typ: TypeIndex) -> Option<TypeIndex> {
+    match type_finder.find(typ).ok()?.parse().ok()? {
+        TypeData::Class(ClassType { fields: Some(fields), .. }) => Some(fields),
+        TypeData::Class(ClassType { name, .. }) => classes.get(name.to_string().as_ref()).copied(),
+        _ => None,
     }
+}
 
-    let mut symbol_extracted: SymbolStore = HashMap::new();
-    let addr_map = pdb.address_map()?;
-    let glosym = pdb.global_symbols()?;
-    let mut symbols = glosym.iter();
-    while let Some(symbol) = symbols.next().unwrap() {
-        match symbol.parse() {
-            Ok(SymbolData::PublicSymbol(data)) => {
-                let name = symbol.name().unwrap().to_string();
-                let Rva(rva) = data.offset.to_rva(&addr_map).unwrap_or_default();
-                symbol_extracted.insert(format!("{}", name), rva as u64);
+fn collect_fields(
+    type_finder: &TypeFinder,
+    classes: &ClassFields,
+    fields: TypeIndex,
+    base: u64,
+    struct_fields: &mut HashMap<String, (String, u64)>,
+) {
+    // Members of anonymous unions/structs are flattened into the parent like WinDbg's dt,
+    // so union members all end up with the same offset. Named members win on conflict.
+    let mut next = Some(fields);
+    while let Some(fields) = next.take() {
+        let Ok(TypeData::FieldList(list)) = type_finder.find(fields).and_then(|t| t.parse()) else {
+            break;
+        };
+        for field in list.fields {
+            if let TypeData::Member(member) = field {
+                let offset = base + member.offset as u64;
+                let mem_typ = get_type_as_str(type_finder, &member.field_type);
+                struct_fields.insert(format!("{}", member.name), (mem_typ, offset));
+                if let Some(inner) = anonymous_fields(type_finder, member.field_type) {
+                    let mut inner_fields = HashMap::new();
+                    collect_fields(type_finder, classes, inner, offset, &mut inner_fields);
+                    for (name, info) in inner_fields {
+                        struct_fields.entry(name).or_insert(info);
+                    }
+                }
+            } else if let TypeData::BaseClass(base_class) = field
+                && let Some(inner) = base_class_fields(type_finder, classes, base_class.base_class)
+            {
+                // C++ classes of win32k inherit members, they sit at the base class offset
+                let mut inner_fields = HashMap::new();
+                collect_fields(type_finder, classes, inner, base + base_class.offset as u64, &mut inner_fields);
+                for (name, info) in inner_fields {
+                    struct_fields.entry(name).or_insert(info);
+                }
             }
-            _ => {}
         }
+        // large field lists are split into a chain
+        next = list.continuation;
     }
+}
 
-    let mut struct_extracted: StructStore = HashMap::new();
-    iter = type_information.iter();
-    while let Some(typ) = iter.next().unwrap() {
-        match typ.parse() {
-            Ok(TypeData::Class(ClassType {
-                name,
-                fields: Some(fields),
-                size,
-                ..
-            })) => {
-                let mut struct_fields = HashMap::new();
-                struct_fields.insert("struct_size".to_string(), ("U32".to_string(), size as u64));
-                match type_finder.find(fields).unwrap().parse().unwrap() {
-                    TypeData::FieldList(list) => {
-                        for field in list.fields {
-                            if let TypeData::Member(member) = field {
-                                let mem_typ = get_type_as_str(&type_finder, &member.field_type);
-                                struct_fields.insert(
-                                    format!("{}", member.name),
-                                    (mem_typ, member.offset as u64),
-                                );
-                            }
-                        }
-                    }
-                    _ => {}
-                }
-                struct_extracted.insert(format!("{}", name), struct_fields);
+pub fn pdb_download_url(server: &str, codeview: &CodeView) -> String {
+    format!(
+        "{}/{}/{}{:X}/{}",
+        server.trim_end_matches('/'), codeview.pdb_name, codeview.guid, codeview.age, codeview.pdb_name
+    )
+}
+
+fn expand_cab(data: Vec<u8>) -> error::Result<Vec<u8>> {
+    // .pd_ files are cabinets holding the single pdb, anything else is returned untouched
+    if !data.starts_with(b"MSCF") {
+        return Ok(data);
+    }
+    let mut cabinet = cab::Cabinet::new(io::Cursor::new(data))?;
+    let name = cabinet
+        .folder_entries()
+        .flat_map(|folder| folder.file_entries())
+        .map(|file| file.name().to_string())
+        .next()
+        .ok_or_else(|| error::Error::Parse("Empty cabinet".into()))?;
+    let mut expanded = Vec::new();
+    io::copy(&mut cabinet.read_file(&name)?, &mut expanded)?;
+    Ok(expanded)
+}
+
+fn fetch_pdb(
+    client: &reqwest::blocking::Client,
+    server: &str,
+    codeview: &CodeView,
+    retries: u32,
+) -> error::Result<Vec<u8>> {
+    let downloadurl = pdb_download_url(server, codeview);
+    match fetch_file(client, &downloadurl, retries) {
+        Err(error::Error::NotIndexed(_)) => {
+            // some pdbs are only stored compressed, ntkrnlmp.pdb -> ntkrnlmp.pd_
+            let mut compressed = downloadurl.clone();
+            compressed.pop();
+            compressed.push('_');
+            debug!("Not found, trying the compressed pdb: {}", compressed);
+            match fetch_file(client, &compressed, retries) {
+                Err(error::Error::NotIndexed(_)) => Err(error::Error::NotIndexed(downloadurl)),
+                result => result,
+            }
+        }
+        result => result,
+    }
+}
+
+fn pe_codeview(pe_path: &Path) -> error::Result<CodeView> {
+    PeFile::open(pe_path)
+        .and_then(|pe| pe.codeview())
+        .map_err(|e| error::Error::Parse(format!("Cannot find the pdb of {}: {}", pe_path.display(), e)))
+}
+
+/// Where download_pdb saves the pdb of a PE: next to it, under the name in its CodeView entry.
+pub fn pdb_path(pe_path: &Path) -> error::Result<PathBuf> {
+    Ok(pe_path.with_file_name(pe_codeview(pe_path)?.pdb_name))
+}
+
+// The pdb is saved next to the PE and its bytes returned, so it need not be read back
+fn download_pdb_data(
+    client: &reqwest::blocking::Client,
+    servers: &[String],
+    pe_path: &Path,
+    retries: u32,
+) -> error::Result<(PathBuf, Vec<u8>)> {
+    // Fetch the pdb named by the PE's CodeView entry and save it next to the PE
+    // ntoskrnl.exe -> ntkrnlmp.pdb
+    // win32k.sys -> win32k.pdb
+    // Servers are tried in order, the first one that has the pdb wins.
+    let codeview = pe_codeview(pe_path)?;
+    let pdb_path = pe_path.with_file_name(&codeview.pdb_name);
+
+    let mut last_error = error::Error::Download("No symbol server to download the pdb from".into());
+    for server in servers {
+        match fetch_pdb(client, server, &codeview, retries) {
+            Ok(data) => {
+                let data = expand_cab(data)?;
+                check_free_space(pe_path.parent().unwrap_or(Path::new(".")), data.len() as u64)?;
+                write_atomically(&pdb_path, &data)?;
+                return Ok((pdb_path, data));
+            }
+            Err(e) => {
+                warn!("Failed to get {} from {}: {}", codeview.pdb_name, server, e);
+                last_error = e;
             }
-            _ => {}
         }
     }
+    Err(last_error)
+}
+
+pub fn download_pdb(
+    client: &reqwest::blocking::Client,
+    servers: &[String],
+    pe_path: &Path,
+    retries: u32,
+) -> error::Result<PathBuf> {
+    download_pdb_data(client, servers, pe_path, retries).map(|(pdb_path, _)| pdb_path)
+}
+
+pub fn parse_pdb(
+    client: &reqwest::blocking::Client,
+    servers: &[String],
+    ntoskrnl_path: &Path,
+    retries: u32,
+) -> error::Result<PdbStore> {
+    let (_, data) = download_pdb_data(client, servers, ntoskrnl_path, retries)?;
+    parse_pdb_from_bytes(&data)
+}
+
+/// The pdb named by the PE's CodeView entry when it is already next to the PE,
+/// from an earlier run or put there by hand. One of another build, with a
+/// different GUID, does not count.
+pub fn local_pdb(pe_path: &Path) -> Option<PathBuf> {
+    let codeview = pe_codeview(pe_path).ok()?;
+    let pdb_path = pe_path.with_file_name(&codeview.pdb_name);
+    let mut pdb = PDB::open(File::open(&pdb_path).ok()?).ok()?;
+    // only the GUID, the age in the pdb information stream can be newer than
+    // the one the PE records
+    let guid = pdb.pdb_information().ok()?.guid.to_string();
+    if !guid.eq_ignore_ascii_case(&codeview.formatted_guid()) {
+        debug!("{} is not the pdb of {}: GUID {}", pdb_path.display(), pe_path.display(), guid);
+        return None;
+    }
+    Some(pdb_path)
+}
+
+/// Build the store from a pdb already on disk.
+pub fn read_pdb(pdb_path: &Path) -> error::Result<PdbStore> {
+    read_pdb_source(File::open(pdb_path)?)
+}
+
+/// Build the store from the bytes of a pdb, e.g. one downloaded or embedded in memory.
+pub fn parse_pdb_from_bytes(data: &[u8]) -> error::Result<PdbStore> {
+    read_pdb_source(io::Cursor::new(data))
+}
+
+fn read_pdb_source<'s, S: pdb::Source<'s> + 's>(source: S) -> error::Result<PdbStore> {
+    let mut pdb = PDB::open(source)?;
+
+    if let (Ok(info), Ok(dbi)) = (pdb.pdb_information(), pdb.debug_information()) {
+        debug!(
+            "PDB for {}, guid: {}, age: {}",
+            dbi.machine_type()?,
+            info.guid,
+            dbi.age().unwrap_or(0)
+        );
+    }
+
+    // A stripped or partial pdb may lack the type or the symbol streams, what
+    // can be read is kept and the rest left empty
+    let types = read_types(&mut pdb);
+    let symbols = read_symbols(&mut pdb);
+    let (mut store, symbol_extracted) = match (types, symbols) {
+        (Ok(types), Ok(symbols)) => (types, symbols),
+        (Ok(types), Err(e)) => {
+            warn!("Cannot read the symbols of the pdb, only its types are used: {}", e);
+            (types, HashMap::new())
+        }
+        (Err(e), Ok(symbols)) => {
+            warn!("Cannot read the types of the pdb, only its symbols are used: {}", e);
+            return Ok(PdbStore {
+                symbols,
+                structs: HashMap::new(),
+                unions: HashSet::new(),
+                enums: HashMap::new(),
+            });
+        }
+        (Err(types), Err(symbols)) => {
+            return Err(error::Error::Parse(format!(
+                "Cannot read the types ({}) nor the symbols ({}) of the pdb",
+                types, symbols
+            )));
+        }
+    };
 
     {
         // https://github.com/Zer0Mem0ry/ntoskrnl/blob/master/Include/mm.h#L1107
@@ -567,11 +1030,371 @@ pub fn parse_pdb(ntoskrnl_path: &Path) -> BoxResult<PdbStore> {
             "CurrentTime".to_string(),
             ("_LARGE_INTEGER".to_string(), 0x20),
         );
-        struct_extracted.insert("_UNLOADED_DRIVERS".to_string(), unload_driver_member);
+        store.structs.insert("_UNLOADED_DRIVERS".to_string(), unload_driver_member);
+    }
+
+    store.symbols = symbol_extracted;
+    Ok(store)
+}
+
+// The structs, unions and enums of the pdb, symbols are left to read_symbols
+fn read_types<'s, S: pdb::Source<'s> + 's>(pdb: &mut PDB<'s, S>) -> pdb::Result<PdbStore> {
+    let type_information = pdb.type_information()?;
+    let mut type_finder = type_information.type_finder();
+    let mut classes = ClassFields::new();
+    let mut iter = type_information.iter();
+    while let Some(typ) = iter.next()? {
+        type_finder.update(&iter);
+        if let Ok(TypeData::Class(ClassType { name, fields: Some(fields), .. })) = typ.parse() {
+            classes.insert(name.to_string().into_owned(), fields);
+        }
     }
 
+    let mut struct_extracted: StructStore = HashMap::new();
+    let mut unions = HashSet::new();
+    let mut enums = EnumStore::new();
+    iter = type_information.iter();
+    while let Some(typ) = iter.next()? {
+        let (name, fields, size) = match typ.parse() {
+            Ok(TypeData::Class(ClassType {
+                name,
+                fields: Some(fields),
+                size,
+                ..
+            })) => (name, fields, size as u64),
+            Ok(TypeData::Union(UnionType {
+                name,
+                fields,
+                size,
+                properties,
+                ..
+            })) if !properties.forward_reference() => {
+                unions.insert(name.to_string().into_owned());
+                (name, fields, size as u64)
+            }
+            Ok(TypeData::Enumeration(EnumerationType {
+                name,
+                underlying_type,
+                fields,
+                properties,
+                ..
+            })) if !properties.forward_reference() => {
+                let base = get_type_as_str(&type_finder, &underlying_type);
+                let constants = enum_constants(&type_finder, fields);
+                enums.insert(name.to_string().into_owned(), (base, constants));
+                continue;
+            }
+            _ => continue,
+        };
+        let mut struct_fields = HashMap::new();
+        struct_fields.insert("struct_size".to_string(), ("U32".to_string(), size));
+        collect_fields(&type_finder, &classes, fields, 0, &mut struct_fields);
+        struct_extracted.insert(format!("{}", name), struct_fields);
+    }
     Ok(PdbStore {
-        symbols: symbol_extracted,
+        symbols: HashMap::new(),
         structs: struct_extracted,
+        unions,
+        enums,
     })
 }
+
+fn enum_constants(type_finder: &TypeFinder, fields: TypeIndex) -> HashMap<String, i64> {
+    let mut constants = HashMap::new();
+    let mut next = Some(fields);
+    while let Some(fields) = next.take() {
+        let Ok(TypeData::FieldList(list)) = type_finder.find(fields).and_then(|t| t.parse()) else {
+            break;
+        };
+        for field in list.fields {
+            if let TypeData::Enumerate(enumerate) = field {
+                let value = match enumerate.value {
+                    Variant::U8(value) => value as i64,
+                    Variant::U16(value) => value as i64,
+                    Variant::U32(value) => value as i64,
+                    // the bits of the largest unsigned values are kept
+                    Variant::U64(value) => value as i64,
+                    Variant::I8(value) => value as i64,
+                    Variant::I16(value) => value as i64,
+                    Variant::I32(value) => value as i64,
+                    Variant::I64(value) => value,
+                };
+                constants.insert(enumerate.name.to_string().into_owned(), value);
+            }
+        }
+        next = list.continuation;
+    }
+    constants
+}
+
+fn read_symbols<'s, S: pdb::Source<'s> + 's>(pdb: &mut PDB<'s, S>) -> pdb::Result<SymbolStore> {
+    let mut symbol_extracted: SymbolStore = HashMap::new();
+    let addr_map = pdb.address_map()?;
+    let glosym = pdb.global_symbols()?;
+    let mut symbols = glosym.iter();
+    while let Some(symbol) = symbols.next()? {
+        // public symbols cover exported functions and variables,
+        // global data symbols fill in the variables that are not public
+        let (offset, public) = match symbol.parse() {
+            Ok(SymbolData::PublicSymbol(data)) => (data.offset, true),
+            Ok(SymbolData::DataSymbol(data)) if data.global => (data.offset, false),
+            _ => continue,
+        };
+        let name = symbol.name()?.to_string().into_owned();
+        let Rva(rva) = offset.to_rva(&addr_map).unwrap_or_default();
+        if public {
+            symbol_extracted.insert(name, rva as u64);
+        } else {
+            symbol_extracted.entry(name).or_insert(rva as u64);
+        }
+    }
+    Ok(symbol_extracted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A tiny x64 image and its pdb, tests/fixtures/fixture.ll has the types and how they are built
+    const FIXTURE_PDB: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/fixture.pdb");
+
+    fn fixture() -> PdbStore {
+        read_pdb(Path::new(FIXTURE_PDB)).unwrap()
+    }
+
+    #[test]
+    fn reads_struct_offsets_and_sizes() {
+        let store = fixture();
+        assert_eq!(store.get_offset("_EPROCESS.Pcb"), Some(0x0));
+        assert_eq!(store.get_offset("_EPROCESS.UniqueProcessId"), Some(0x30));
+        assert_eq!(store.get_offset("_EPROCESS.ActiveProcessLinks"), Some(0x38));
+        assert_eq!(store.get_offset("_EPROCESS.Token"), Some(0x50));
+        assert_eq!(store.get_offset("_KPROCESS.DirectoryTableBase"), Some(0x28));
+        assert_eq!(store.struct_size("_EPROCESS"), Some(0x70));
+        assert_eq!(store.struct_size("_LIST_ENTRY"), Some(0x10));
+
+        let token = store.get_field("_EPROCESS", "Token").unwrap();
+        assert_eq!(token.type_name, "_EX_FAST_REF");
+        assert_eq!(token.size, Some(8));
+        let image_file_name = store.get_field("_EPROCESS", "ImageFileName").unwrap();
+        assert_eq!(image_file_name.type_name, "UChar[15]");
+        assert!(store.get_field("_LIST_ENTRY", "Flink").unwrap().is_pointer);
+    }
+
+    #[test]
+    fn reads_bitfields_of_anonymous_unions() {
+        let store = fixture();
+        // the anonymous union and struct are flattened into _EPROCESS
+        assert_eq!(store.get_offset("_EPROCESS.Flags"), Some(0x48));
+        assert_eq!(
+            store.get_field_offset("_EPROCESS", "ProcessExiting"),
+            Some(MemberLocation::Bitfield { byte: 0x48, bit: 2, len: 1 })
+        );
+        assert_eq!(
+            store.get_field_offset("_EPROCESS", "ExceptionPortState"),
+            Some(MemberLocation::Bitfield { byte: 0x48, bit: 3, len: 3 })
+        );
+    }
+
+    #[test]
+    fn reads_union_members() {
+        let store = fixture();
+        assert_eq!(store.struct_size("_EX_FAST_REF"), Some(8));
+        assert!(store.unions.contains("_EX_FAST_REF"));
+        assert!(!store.unions.contains("_EPROCESS"));
+        assert_eq!(store.get_field_offset("_EX_FAST_REF", "Object"), Some(MemberLocation::Byte(0)));
+        assert_eq!(store.get_field_offset("_EX_FAST_REF", "Value"), Some(MemberLocation::Byte(0)));
+        assert_eq!(
+            store.get_field_offset("_EX_FAST_REF", "RefCnt"),
+            Some(MemberLocation::Bitfield { byte: 0, bit: 0, len: 4 })
+        );
+    }
+
+    #[test]
+    fn reads_enums() {
+        let store = fixture();
+        let (base, constants) = &store.enums["_POOL_TYPE"];
+        assert_eq!(base, "I32");
+        assert_eq!(constants["NonPagedPool"], 0);
+        assert_eq!(constants["PagedPool"], 1);
+        assert_eq!(constants["NonPagedPoolNx"], 512);
+        assert_eq!(store.get_field("_EPROCESS", "PoolType").unwrap().type_name, "_POOL_TYPE");
+        assert_eq!(store.get_field("_EPROCESS", "PoolType").unwrap().size, Some(4));
+    }
+
+    #[test]
+    fn reads_symbol_rvas() {
+        let store = fixture();
+        assert_eq!(store.get_symbol_rva("KiSystemStartup"), Some(0x1000));
+        assert_eq!(store.get_symbol_rva("PsInitialSystemProcess"), Some(0x3000));
+        assert_eq!(store.get_symbol_rva("PsLoadedModuleList"), Some(0x3080));
+        assert_eq!(store.get_symbol_rva("PsNoSuchSymbol"), None);
+    }
+
+    // (member, type, offset) of a struct
+    type Members<'a> = &'a [(&'a str, &'a str, u64)];
+
+    fn synthetic(structs: &[(&str, Members)]) -> PdbStore {
+        let structs = structs
+            .iter()
+            .map(|(struct_name, members)| {
+                let members = members
+                    .iter()
+                    .map(|(member, memtype, offset)| (member.to_string(), (memtype.to_string(), *offset)))
+                    .collect();
+                (struct_name.to_string(), members)
+            })
+            .collect();
+        PdbStore {
+            symbols: HashMap::new(),
+            structs,
+            unions: HashSet::new(),
+            enums: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn walks_two_levels_of_embedded_structs() {
+        let store = synthetic(&[
+            ("_EPROCESS", &[("Lock", "_EX_PUSH_LOCK", 0x0), ("Pcb", "_KPROCESS", 0x10), ("Job", "_EJOB*", 0x80)]),
+            ("_KPROCESS", &[("struct_size", "U32", 0x40), ("Header", "_DISPATCHER_HEADER", 0x20)]),
+            (
+                "_DISPATCHER_HEADER",
+                &[
+                    ("struct_size", "U32", 0x18),
+                    ("DebugActive", "UChar:1:2", 0x3),
+                    ("WaitListHead", "_LIST_ENTRY", 0x8),
+                ],
+            ),
+            ("_LIST_ENTRY", &[("struct_size", "U32", 0x10), ("Flink", "_LIST_ENTRY*", 0x0)]),
+        ]);
+        // 0x10 + 0x20 + 0x8
+        let wait_list_head = store.get_field("_EPROCESS", "Pcb.Header.WaitListHead").unwrap();
+        assert_eq!(wait_list_head.location, MemberLocation::Byte(0x38));
+        assert_eq!(wait_list_head.type_name, "_LIST_ENTRY");
+        assert_eq!(wait_list_head.size, Some(0x10));
+        assert_eq!(
+            store.get_field_offset("_EPROCESS", "Pcb.Header.DebugActive"),
+            Some(MemberLocation::Bitfield { byte: 0x33, bit: 1, len: 2 })
+        );
+        let flink = store.get_field_offset("_EPROCESS", "Pcb.Header.WaitListHead.Flink");
+        assert_eq!(flink, Some(MemberLocation::Byte(0x38)));
+        assert_eq!(store.get_field_offset("_EPROCESS", "Pcb.Header"), Some(MemberLocation::Byte(0x30)));
+
+        // a missing member anywhere along the path, or a pointer to walk through
+        assert_eq!(store.get_field("_EPROCESS", "Pcb.Header.NoSuchMember"), None);
+        assert_eq!(store.get_field("_EPROCESS", "Pcb.NoSuchMember.Lock"), None);
+        assert_eq!(store.get_field("_EPROCESS", "Job.Lock"), None);
+        assert_eq!(store.get_field("_EPROCESS", "Pcb.Header.DebugActive.Lock"), None);
+    }
+
+    #[test]
+    fn walks_embedded_structs_of_a_pdb() {
+        let store = fixture();
+        assert_eq!(store.get_field_offset("_EPROCESS", "Pcb.Header.SignalState"), Some(MemberLocation::Byte(0x4)));
+        assert_eq!(store.get_field_offset("_EPROCESS", "Pcb.Header.WaitListHead"), Some(MemberLocation::Byte(0x8)));
+        assert_eq!(store.get_field_offset("_EPROCESS", "Pcb.DirectoryTableBase"), Some(MemberLocation::Byte(0x28)));
+    }
+
+    #[test]
+    fn symbol_rvas_fit_in_32_bits() {
+        let store = PdbStore {
+            symbols: HashMap::from([("PsInitialSystemProcess".to_string(), 0xcfc420), ("Far".to_string(), 1 << 32)]),
+            structs: HashMap::new(),
+            unions: HashSet::new(),
+            enums: HashMap::new(),
+        };
+        assert_eq!(store.get_symbol_rva("PsInitialSystemProcess"), Some(0xcfc420));
+        assert_eq!(store.get_symbol_rva("psinitialsystemprocess"), None);
+        assert_eq!(store.get_symbol_rva("Far"), None);
+    }
+
+    #[test]
+    fn parses_the_same_from_bytes() {
+        let from_file = fixture();
+        let from_bytes = parse_pdb_from_bytes(&std::fs::read(FIXTURE_PDB).unwrap()).unwrap();
+        assert_eq!(from_bytes.symbols, from_file.symbols);
+        assert_eq!(from_bytes.structs, from_file.structs);
+        assert_eq!(from_bytes.unions, from_file.unions);
+        assert_eq!(from_bytes.enums, from_file.enums);
+    }
+
+    #[test]
+    fn rejects_what_is_not_a_pdb() {
+        assert!(parse_pdb_from_bytes(b"not a pdb").is_err());
+    }
+
+    #[test]
+    fn expands_compressed_pdbs() {
+        // a .pd_ as the symbol server serves it, the pdb alone in an MSZIP cabinet
+        let pdb = std::fs::read(FIXTURE_PDB).unwrap();
+        let mut builder = cab::CabinetBuilder::new();
+        builder.add_folder(cab::CompressionType::MsZip).add_file("fixture.pdb");
+        let mut writer = builder.build(io::Cursor::new(Vec::new())).unwrap();
+        while let Some(mut file) = writer.next_file().unwrap() {
+            file.write_all(&pdb).unwrap();
+        }
+        let compressed = writer.finish().unwrap().into_inner();
+        assert!(compressed.starts_with(b"MSCF"));
+        assert_ne!(compressed, pdb);
+
+        let expanded = expand_cab(compressed).unwrap();
+        assert_eq!(expanded, pdb);
+        assert!(parse_pdb_from_bytes(&expanded).is_ok());
+    }
+
+    #[test]
+    fn leaves_uncompressed_pdbs_alone() {
+        let pdb = std::fs::read(FIXTURE_PDB).unwrap();
+        assert_eq!(expand_cab(pdb.clone()).unwrap(), pdb);
+    }
+
+    #[test]
+    fn reads_inherited_members_of_classes() {
+        // win32k's C++ classes get the members of their base classes, at the base offset
+        let pdb = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/win32k.pdb");
+        let store = read_pdb(Path::new(pdb)).unwrap();
+        assert_eq!(store.get_offset("tagWND.struct_size"), Some(0x28));
+        assert_eq!(store.get_offset("tagWND.h"), Some(0x0));
+        assert_eq!(store.get_offset("tagWND.pti"), Some(0x10));
+        assert_eq!(store.get_offset("tagWND.state"), Some(0x18));
+        assert_eq!(store.get_field("tagWND", "spwndNext").unwrap().type_name, "tagWND*");
+    }
+
+    #[test]
+    fn default_fields_follow_the_pe() {
+        let has = |fields: &[(String, String)], struct_name: &str, member: &str| {
+            fields.iter().any(|(s, m)| s == struct_name && m == member)
+        };
+        for pe_name in ["win32k.sys", "win32kbase.sys", "Win32kFull.sys"] {
+            let list = default_fields_for(pe_name);
+            assert!(has(&list.fields, "tagWND", "spwndNext"), "{}", pe_name);
+            assert!(!has(&list.fields, "_EPROCESS", "UniqueProcessId"), "{}", pe_name);
+            assert!(!list.symbols.contains(&"PsLoadedModuleList".to_string()), "{}", pe_name);
+        }
+        let ci = default_fields_for("CI.dll");
+        assert!(has(&ci.fields, "_FILE_OBJECT", "FileName"));
+        assert_eq!(ci.symbols, ["g_CiOptions"]);
+        let ntoskrnl = default_fields_for("ntoskrnl.exe");
+        assert!(has(&ntoskrnl.fields, "_EPROCESS", "UniqueProcessId"));
+        assert!(ntoskrnl.symbols.contains(&"PsLoadedModuleList".to_string()));
+        assert_eq!(default_fields_for("ntkrla57.exe"), default_fields());
+    }
+
+    #[test]
+    fn parses_field_lists() {
+        let content = "# comment\n\n_EPROCESS.Pcb.DirectoryTableBase\n  tagWND.state  \ngpsi\n";
+        let list = parse_fields(content, "test").unwrap();
+        assert_eq!(
+            list.fields,
+            [
+                ("_EPROCESS".to_string(), "Pcb.DirectoryTableBase".to_string()),
+                ("tagWND".to_string(), "state".to_string()),
+            ]
+        );
+        assert_eq!(list.symbols, ["gpsi"]);
+        let e = parse_fields("tagWND.state\ntagWND state\n", "win32k.txt").unwrap_err();
+        assert_eq!(e.to_string(), "win32k.txt:2: expected Struct.Field or a symbol, got \"tagWND state\"");
+        assert!(parse_fields(".state", "test").is_err());
+    }
+}
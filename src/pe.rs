@@ -0,0 +1,425 @@
+use std::fs;
+use std::path::Path;
+
+use crate::error::{Error, Result};
+use crate::utils::version::Version4;
+
+const IMAGE_DIRECTORY_ENTRY_EXPORT: usize = 0;
+const IMAGE_DIRECTORY_ENTRY_RESOURCE: usize = 2;
+const IMAGE_DIRECTORY_ENTRY_BASERELOC: usize = 5;
+const IMAGE_DIRECTORY_ENTRY_DEBUG: usize = 6;
+pub const IMAGE_FILE_MACHINE_I386: u16 = 0x14c;
+pub const IMAGE_FILE_MACHINE_AMD64: u16 = 0x8664;
+pub const IMAGE_FILE_MACHINE_ARM64: u16 = 0xaa64;
+const IMAGE_DEBUG_TYPE_CODEVIEW: u32 = 2;
+const IMAGE_REL_BASED_HIGHLOW: u16 = 3;
+const IMAGE_REL_BASED_DIR64: u16 = 10;
+const RT_VERSION: u32 = 16;
+const VS_FIXEDFILEINFO_SIGNATURE: u32 = 0xfeef04bd;
+
+fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    let bytes = data.get(offset..offset + 2)?;
+    Some(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset..offset + 4)?;
+    Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+// A PE that is cut short or does not add up
+fn malformed(what: &str) -> Error {
+    Error::Parse(what.to_string())
+}
+
+/// Short name of a PE machine type, as used in output paths and offsets.json
+pub fn arch_name(machine: u16) -> Option<&'static str> {
+    match machine {
+        IMAGE_FILE_MACHINE_AMD64 => Some("x64"),
+        IMAGE_FILE_MACHINE_ARM64 => Some("arm64"),
+        IMAGE_FILE_MACHINE_I386 => Some("x86"),
+        _ => None,
+    }
+}
+
+struct Section {
+    virtual_address: u32,
+    raw_offset: u32,
+    raw_size: u32,
+}
+
+// The PDB a PE was built with, from the CodeView entry of its debug directory
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CodeView {
+    pub pdb_name: String,
+    // GUID as uppercase hex without dashes, the way the symbol server wants it
+    pub guid: String,
+    pub age: u32,
+}
+
+impl CodeView {
+    /// The GUID in its usual 8-4-4-4-12 form, e.g. 3844DBB9-2017-4967-BE7A-A4A2C20430FA
+    pub fn formatted_guid(&self) -> String {
+        let guid = &self.guid;
+        format!("{}-{}-{}-{}-{}", &guid[0..8], &guid[8..12], &guid[12..16], &guid[16..20], &guid[20..32])
+    }
+}
+
+pub struct PeFile {
+    data: Vec<u8>,
+    pub machine: u16,
+    sections: Vec<Section>,
+    // (rva, size) of every data directory
+    data_directories: Vec<(u32, u32)>,
+}
+
+impl PeFile {
+    pub fn open(path: &Path) -> Result<Self> {
+        Self::parse(fs::read(path)?)
+    }
+
+    pub fn parse(data: Vec<u8>) -> Result<Self> {
+        if data.get(0..2) != Some(b"MZ") {
+            return Err(malformed("No MZ signature"));
+        }
+        let pe_offset = read_u32(&data, 0x3c).ok_or_else(|| malformed("Truncated DOS header"))? as usize;
+        if data.get(pe_offset..pe_offset + 4) != Some(b"PE\0\0") {
+            return Err(malformed("No PE signature"));
+        }
+
+        // IMAGE_FILE_HEADER follows the signature
+        let file_header = pe_offset + 4;
+        let machine = read_u16(&data, file_header).ok_or_else(|| malformed("Truncated file header"))?;
+        let number_of_sections = read_u16(&data, file_header + 2).ok_or_else(|| malformed("Truncated file header"))?;
+        let optional_header_size = read_u16(&data, file_header + 16).ok_or_else(|| malformed("Truncated file header"))?;
+
+        let optional_header = file_header + 20;
+        let magic = read_u16(&data, optional_header).ok_or_else(|| malformed("Truncated optional header"))?;
+        // NumberOfRvaAndSizes is right before the data directories
+        let directories = match magic {
+            0x10b => optional_header + 96,  // PE32
+            0x20b => optional_header + 112, // PE32+
+            _ => return Err(Error::Parse(format!("Unknown optional header magic 0x{:x}", magic))),
+        };
+        let directory_count = read_u32(&data, directories - 4).ok_or_else(|| malformed("Truncated optional header"))?;
+        let data_directories = (0..directory_count as usize)
+            .map_while(|i| Some((read_u32(&data, directories + i * 8)?, read_u32(&data, directories + i * 8 + 4)?)))
+            .collect();
+
+        let section_table = optional_header + optional_header_size as usize;
+        let sections = (0..number_of_sections as usize)
+            .map(|i| {
+                let header = section_table + i * 40;
+                Some(Section {
+                    virtual_address: read_u32(&data, header + 12)?,
+                    raw_size: read_u32(&data, header + 16)?,
+                    raw_offset: read_u32(&data, header + 20)?,
+                })
+            })
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(|| malformed("Truncated section table"))?;
+
+        Ok(PeFile {
+            data,
+            machine,
+            sections,
+            data_directories,
+        })
+    }
+
+    pub fn rva_to_offset(&self, rva: u32) -> Option<usize> {
+        self.sections.iter().find_map(|section| {
+            let start = section.virtual_address;
+            // only the part backed by the file can be read
+            if rva >= start && rva - start < section.raw_size {
+                // a malformed section header can put it past what a file offset can hold
+                section.raw_offset.checked_add(rva - start).map(|offset| offset as usize)
+            } else {
+                None
+            }
+        })
+    }
+
+    pub fn data_directory(&self, index: usize) -> Option<(u32, u32)> {
+        self.data_directories
+            .get(index)
+            .copied()
+            .filter(|&(rva, size)| rva != 0 && size != 0)
+    }
+
+    /// `len` bytes of the image from `rva`, None when they are not all in the file.
+    pub fn read_rva(&self, rva: u32, len: usize) -> Option<&[u8]> {
+        let start = self.rva_to_offset(rva)?;
+        let last = rva.checked_add(u32::try_from(len).ok()?.checked_sub(1)?)?;
+        // the last byte has to be in the same section, right after the others
+        if self.rva_to_offset(last)? != start + len - 1 {
+            return None;
+        }
+        self.data.get(start..start + len)
+    }
+
+    /// Where the loader patches the image when it is not loaded at its preferred
+    /// base, as (rva, size) of every absolute address in the base relocations.
+    pub fn relocations(&self) -> Result<Vec<(u32, u32)>> {
+        let Some((rva, size)) = self.data_directory(IMAGE_DIRECTORY_ENTRY_BASERELOC) else {
+            return Ok(Vec::new());
+        };
+        let start = self.rva_to_offset(rva).ok_or_else(|| malformed("Relocation directory is outside of any section"))?;
+        let end = start + size as usize;
+
+        // IMAGE_BASE_RELOCATION blocks, a page rva and its 12 bit offsets tagged with a type
+        let mut relocations = Vec::new();
+        let mut block = start;
+        while block + 8 <= end {
+            let page = read_u32(&self.data, block).ok_or_else(|| malformed("Truncated relocation block"))?;
+            let block_size = read_u32(&self.data, block + 4)
+                .ok_or_else(|| malformed("Truncated relocation block"))? as usize;
+            if block_size < 8 {
+                break;
+            }
+            for entry in (block + 8..(block + block_size).min(end)).step_by(2) {
+                let entry = read_u16(&self.data, entry).ok_or_else(|| malformed("Truncated relocation block"))?;
+                let size = match entry >> 12 {
+                    IMAGE_REL_BASED_HIGHLOW => 4,
+                    IMAGE_REL_BASED_DIR64 => 8,
+                    // padding, and types not used by x86, x64 or arm64 images
+                    _ => continue,
+                };
+                relocations.push((page + (entry & 0xfff) as u32, size));
+            }
+            block += block_size;
+        }
+        Ok(relocations)
+    }
+
+    fn read_cstr(&self, offset: usize) -> Option<String> {
+        let bytes = self.data.get(offset..)?;
+        let len = bytes.iter().position(|&b| b == 0)?;
+        Some(String::from_utf8_lossy(&bytes[..len]).into_owned())
+    }
+
+    /// Named exports with their RVA, forwarded exports are left out
+    /// since they have no code in this PE.
+    pub fn exports(&self) -> Result<Vec<(String, u32)>> {
+        let Some((rva, size)) = self.data_directory(IMAGE_DIRECTORY_ENTRY_EXPORT) else {
+            return Ok(Vec::new());
+        };
+        let directory = self.rva_to_offset(rva).ok_or_else(|| malformed("Export directory is outside of any section"))?;
+
+        // IMAGE_EXPORT_DIRECTORY
+        let read = |offset| {
+            read_u32(&self.data, directory + offset).ok_or_else(|| malformed("Truncated export directory"))
+        };
+        let number_of_functions = read(0x14)?;
+        let number_of_names = read(0x18)?;
+        let functions = self.rva_to_offset(read(0x1c)?).ok_or_else(|| malformed("Bad AddressOfFunctions"))?;
+        let names = self.rva_to_offset(read(0x20)?).ok_or_else(|| malformed("Bad AddressOfNames"))?;
+        let ordinals = self.rva_to_offset(read(0x24)?).ok_or_else(|| malformed("Bad AddressOfNameOrdinals"))?;
+
+        let mut exports = Vec::new();
+        for i in 0..number_of_names as usize {
+            let name_rva = read_u32(&self.data, names + i * 4).ok_or_else(|| malformed("Truncated export names"))?;
+            let ordinal = read_u16(&self.data, ordinals + i * 2).ok_or_else(|| malformed("Truncated export ordinals"))?;
+            if ordinal as u32 >= number_of_functions {
+                continue;
+            }
+            let function_rva = read_u32(&self.data, functions + ordinal as usize * 4)
+                .ok_or_else(|| malformed("Truncated export functions"))?;
+            // a function rva inside the export directory points at a forwarder string
+            if function_rva >= rva && function_rva - rva < size {
+                continue;
+            }
+            let name = self
+                .rva_to_offset(name_rva)
+                .and_then(|offset| self.read_cstr(offset))
+                .ok_or_else(|| malformed("Bad export name"))?;
+            exports.push((name, function_rva));
+        }
+        Ok(exports)
+    }
+
+    // The entry `id` leads to in a resource directory, the first one when None.
+    // Returns its offset and whether it is another directory rather than data.
+    fn resource_child(&self, root: usize, directory: usize, id: Option<u32>) -> Option<(usize, bool)> {
+        let named = read_u16(&self.data, directory + 12)? as usize;
+        let ids = read_u16(&self.data, directory + 14)? as usize;
+        (0..named + ids).find_map(|i| {
+            let entry = directory + 16 + i * 8;
+            if id.is_some_and(|id| read_u32(&self.data, entry) != Some(id)) {
+                return None;
+            }
+            let target = read_u32(&self.data, entry + 4)?;
+            Some((root + (target & 0x7fff_ffff) as usize, target & 0x8000_0000 != 0))
+        })
+    }
+
+    /// The file version from the VS_FIXEDFILEINFO of the version resource, the
+    /// one Explorer shows and winbindex records.
+    pub fn file_version(&self) -> Result<Version4> {
+        let (rva, _) = self
+            .data_directory(IMAGE_DIRECTORY_ENTRY_RESOURCE)
+            .ok_or_else(|| malformed("PE has no resource directory"))?;
+        let root = self.rva_to_offset(rva).ok_or_else(|| malformed("Resource directory is outside of any section"))?;
+
+        // type, then name and language, a version resource has a single one of each
+        let (mut entry, mut is_directory) = self
+            .resource_child(root, root, Some(RT_VERSION))
+            .ok_or_else(|| malformed("PE has no version resource"))?;
+        for _ in 0..2 {
+            if !is_directory {
+                break;
+            }
+            (entry, is_directory) = self
+                .resource_child(root, entry, None)
+                .ok_or_else(|| malformed("Empty version resource"))?;
+        }
+        if is_directory {
+            return Err(malformed("Version resource is nested too deep"));
+        }
+
+        let data_rva = read_u32(&self.data, entry).ok_or_else(|| malformed("Truncated resource entry"))?;
+        let size = read_u32(&self.data, entry + 4).ok_or_else(|| malformed("Truncated resource entry"))? as usize;
+        let start = self
+            .rva_to_offset(data_rva)
+            .ok_or_else(|| malformed("Version resource is outside of any section"))?;
+        let raw = self.data.get(start..start + size).ok_or_else(|| malformed("Truncated version resource"))?;
+
+        // VS_FIXEDFILEINFO follows the VS_VERSION_INFO key, aligned on 4 bytes
+        let fixed = (0..raw.len())
+            .step_by(4)
+            .find(|&offset| read_u32(raw, offset) == Some(VS_FIXEDFILEINFO_SIGNATURE))
+            .ok_or_else(|| malformed("Version resource has no VS_FIXEDFILEINFO"))?;
+        let ms = read_u32(raw, fixed + 8).ok_or_else(|| malformed("Truncated VS_FIXEDFILEINFO"))?;
+        let ls = read_u32(raw, fixed + 12).ok_or_else(|| malformed("Truncated VS_FIXEDFILEINFO"))?;
+        Ok(Version4 {
+            major: ms >> 16,
+            minor: ms & 0xffff,
+            build: ls >> 16,
+            revision: ls & 0xffff,
+        })
+    }
+
+    pub fn codeview(&self) -> Result<CodeView> {
+        let (rva, size) = self
+            .data_directory(IMAGE_DIRECTORY_ENTRY_DEBUG)
+            .ok_or_else(|| malformed("PE has no debug directory"))?;
+        let start = self.rva_to_offset(rva).ok_or_else(|| malformed("Debug directory is outside of any section"))?;
+
+        // IMAGE_DEBUG_DIRECTORY entries are 28 bytes each
+        for entry in (start..start + size as usize).step_by(28) {
+            if read_u32(&self.data, entry + 12) != Some(IMAGE_DEBUG_TYPE_CODEVIEW) {
+                continue;
+            }
+            let raw_size = read_u32(&self.data, entry + 16)
+                .ok_or_else(|| malformed("Truncated debug directory"))? as usize;
+            let raw_offset = read_u32(&self.data, entry + 24)
+                .ok_or_else(|| malformed("Truncated debug directory"))? as usize;
+            let raw = self
+                .data
+                .get(raw_offset..raw_offset + raw_size)
+                .ok_or_else(|| malformed("Truncated CodeView entry"))?;
+            return parse_rsds(raw);
+        }
+        Err(malformed("PE has no CodeView debug entry"))
+    }
+}
+
+fn parse_rsds(raw: &[u8]) -> Result<CodeView> {
+    // RSDS signature, 16 bytes guid, 4 bytes age, null terminated pdb path
+    if raw.len() < 24 || &raw[0..4] != b"RSDS" {
+        return Err(malformed("CodeView entry is not RSDS"));
+    }
+
+    // the first three guid parts are little endian
+    let guid = [3, 2, 1, 0, 5, 4, 7, 6, 8, 9, 10, 11, 12, 13, 14, 15]
+        .iter()
+        .map(|&i| format!("{:02X}", raw[4 + i]))
+        .collect::<String>();
+    let age = read_u32(raw, 20).ok_or_else(|| malformed("Truncated RSDS entry"))?;
+
+    let raw_name: Vec<u8> = raw[24..].iter().copied().take_while(|&b| b != 0).collect();
+    let pdb_path = String::from_utf8_lossy(&raw_name);
+    // only the file name matters, some PEs carry the full build path
+    let pdb_name = pdb_path.rsplit('\\').next().unwrap_or_default().to_string();
+    if pdb_name.is_empty() {
+        return Err(malformed("RSDS entry has no pdb name"));
+    }
+
+    Ok(CodeView { pdb_name, guid, age })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_sections(sections: Vec<Section>) -> PeFile {
+        PeFile {
+            data: Vec::new(),
+            machine: IMAGE_FILE_MACHINE_AMD64,
+            sections,
+            data_directories: Vec::new(),
+        }
+    }
+
+    const FIXTURE_EXE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/fixture.exe");
+
+    #[test]
+    fn formats_guids_8_4_4_4_12() {
+        let codeview = CodeView {
+            pdb_name: "ntkrnlmp.pdb".to_string(),
+            guid: "3844DBB920174967BE7AA4A2C20430FA".to_string(),
+            age: 1,
+        };
+        assert_eq!(codeview.formatted_guid(), "3844DBB9-2017-4967-BE7A-A4A2C20430FA");
+    }
+
+    #[test]
+    fn parses_rsds_entries() {
+        // the GUID 3844DBB9-2017-4967-BE7A-A4A2C20430FA as it is stored, the first three parts little endian
+        let mut raw = b"RSDS".to_vec();
+        raw.extend([0xb9, 0xdb, 0x44, 0x38, 0x17, 0x20, 0x67, 0x49]);
+        raw.extend([0xbe, 0x7a, 0xa4, 0xa2, 0xc2, 0x04, 0x30, 0xfa]);
+        raw.extend(0x2au32.to_le_bytes());
+        raw.extend(b"d:\\os\\obj\\ntkrnlmp.pdb\0");
+        let codeview = parse_rsds(&raw).unwrap();
+        assert_eq!(codeview.guid, "3844DBB920174967BE7AA4A2C20430FA");
+        assert_eq!(codeview.formatted_guid(), "3844DBB9-2017-4967-BE7A-A4A2C20430FA");
+        assert_eq!(codeview.age, 0x2a);
+        assert_eq!(codeview.pdb_name, "ntkrnlmp.pdb");
+
+        assert!(parse_rsds(b"NB10").is_err());
+        assert!(parse_rsds(&raw[..24]).is_err());
+    }
+
+    #[test]
+    fn reads_the_codeview_entry_of_a_pe() {
+        let pe = PeFile::open(Path::new(FIXTURE_EXE)).unwrap();
+        assert_eq!(arch_name(pe.machine), Some("x64"));
+        let codeview = pe.codeview().unwrap();
+        assert_eq!(codeview.pdb_name, "fixture.pdb");
+        // what llvm-pdbutil dump -summary shows for tests/fixtures/fixture.pdb
+        assert_eq!(codeview.formatted_guid(), "081F2B3E-21C8-B880-4C4C-44205044422E");
+        assert_eq!(codeview.age, 1);
+    }
+
+    #[test]
+    fn rva_to_offset_maps_the_raw_part_of_sections() {
+        let pe = with_sections(vec![
+            Section { virtual_address: 0x1000, raw_offset: 0x400, raw_size: 0x200 },
+            Section { virtual_address: 0x3000, raw_offset: 0x600, raw_size: 0x100 },
+        ]);
+        assert_eq!(pe.rva_to_offset(0x1000), Some(0x400));
+        assert_eq!(pe.rva_to_offset(0x11ff), Some(0x5ff));
+        assert_eq!(pe.rva_to_offset(0x3010), Some(0x610));
+        // past the raw data, or in no section at all
+        assert_eq!(pe.rva_to_offset(0x1200), None);
+        assert_eq!(pe.rva_to_offset(0x500), None);
+    }
+
+    #[test]
+    fn rva_to_offset_rejects_offsets_that_overflow() {
+        let pe = with_sections(vec![Section { virtual_address: 0x1000, raw_offset: 0xffff_ff00, raw_size: 0x200 }]);
+        assert_eq!(pe.rva_to_offset(0x10ff), Some(0xffff_ffff));
+        assert_eq!(pe.rva_to_offset(0x1100), None);
+    }
+}
@@ -0,0 +1,89 @@
+//! Byte signatures of functions, to find them by pattern scanning where there are
+//! no symbols, e.g. "48 89 5C 24 ?? 57 48 83 EC 20 E8 ?? ?? ?? ??". Bytes that
+//! differ between builds of the same code are wildcards: absolute addresses the
+//! loader relocates, and the pc-relative operands of branches and memory accesses.
+
+use iced_x86::{Decoder, DecoderOptions, OpKind};
+
+use crate::error::{Error, Result};
+use crate::pe::{PeFile, IMAGE_FILE_MACHINE_AMD64, IMAGE_FILE_MACHINE_ARM64, IMAGE_FILE_MACHINE_I386};
+
+// Longest x86 instruction, enough to decode the one the signature ends in
+const MAX_INSTRUCTION_LEN: usize = 15;
+
+/// Signature of the first `len` bytes of the function at `rva`, two hex digits
+/// or ?? per byte. `relocations` is what PeFile::relocations returns.
+pub fn function_signature(pe: &PeFile, relocations: &[(u32, u32)], rva: u32, len: usize) -> Result<String> {
+    // decoding reads past the end when the last instruction is cut
+    let code = (len..=len + MAX_INSTRUCTION_LEN)
+        .rev()
+        .find_map(|available| pe.read_rva(rva, available))
+        .ok_or_else(|| Error::Parse(format!("The {} bytes at 0x{:x} are not in the PE", len, rva)))?;
+
+    let mut wildcards = vec![false; code.len()];
+    for &(relocation, size) in relocations {
+        for byte in relocation..relocation.saturating_add(size) {
+            if let Some(wildcard) = byte.checked_sub(rva).and_then(|index| wildcards.get_mut(index as usize)) {
+                *wildcard = true;
+            }
+        }
+    }
+    match pe.machine {
+        IMAGE_FILE_MACHINE_AMD64 => mask_x86(code, 64, rva, &mut wildcards),
+        IMAGE_FILE_MACHINE_I386 => mask_x86(code, 32, rva, &mut wildcards),
+        IMAGE_FILE_MACHINE_ARM64 => mask_arm64(code, &mut wildcards),
+        machine => return Err(Error::Parse(format!("No signatures for machine 0x{:x}", machine))),
+    }
+
+    Ok(code
+        .iter()
+        .zip(&wildcards)
+        .take(len)
+        .map(|(byte, &wildcard)| if wildcard { "??".to_string() } else { format!("{:02X}", byte) })
+        .collect::<Vec<_>>()
+        .join(" "))
+}
+
+fn mask_x86(code: &[u8], bitness: u32, rva: u32, wildcards: &mut [bool]) {
+    let mut decoder = Decoder::with_ip(bitness, code, rva as u64, DecoderOptions::NONE);
+    let mut start = 0;
+    while decoder.can_decode() {
+        let instruction = decoder.decode();
+        if instruction.is_invalid() {
+            // without the instruction boundaries nothing after this can be masked
+            break;
+        }
+        let offsets = decoder.get_constant_offsets(&instruction);
+        // call, jmp, jcc and loop targets are relative to the next instruction
+        let is_branch = matches!(
+            instruction.op0_kind(),
+            OpKind::NearBranch16 | OpKind::NearBranch32 | OpKind::NearBranch64
+        );
+        if is_branch && offsets.has_immediate() {
+            let operand = start + offsets.immediate_offset();
+            wildcards[operand..operand + offsets.immediate_size()].fill(true);
+        }
+        if instruction.is_ip_rel_memory_operand() && offsets.has_displacement() {
+            let operand = start + offsets.displacement_offset();
+            wildcards[operand..operand + offsets.displacement_size()].fill(true);
+        }
+        start += instruction.len();
+    }
+}
+
+fn mask_arm64(code: &[u8], wildcards: &mut [bool]) {
+    // The pc-relative immediates are spread over the instruction, the whole of it
+    // is a wildcard
+    for (index, word) in code.chunks_exact(4).enumerate() {
+        let word = u32::from_le_bytes([word[0], word[1], word[2], word[3]]);
+        let pc_relative = word & 0x7c00_0000 == 0x1400_0000 // b, bl
+            || word & 0xff00_0010 == 0x5400_0000 // b.cond
+            || word & 0x7e00_0000 == 0x3400_0000 // cbz, cbnz
+            || word & 0x7e00_0000 == 0x3600_0000 // tbz, tbnz
+            || word & 0x1f00_0000 == 0x1000_0000 // adr, adrp
+            || word & 0x3b00_0000 == 0x1800_0000; // ldr literal
+        if pc_relative {
+            wildcards[index * 4..index * 4 + 4].fill(true);
+        }
+    }
+}
@@ -0,0 +1,44 @@
+/// The fileId a symbol server stores a PE under: its TimeDateStamp as 8 uppercase
+/// hex digits followed by its SizeOfImage in lowercase hex without padding,
+/// e.g. 5F4E3A2B9a5000.
+pub fn make_file_id(timestamp: u32, size: u64) -> String {
+    format!("{:08X}{:x}", timestamp, size)
+}
+
+/// Split a fileId back into its timestamp and size, e.g. to make sense of a URL
+/// that failed. Either hex case is accepted.
+pub fn parse_file_id(file_id: &str) -> Option<(u32, u64)> {
+    if file_id.len() <= 8 || !file_id.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    let (timestamp, size) = file_id.split_at(8);
+    Some((u32::from_str_radix(timestamp, 16).ok()?, u64::from_str_radix(size, 16).ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_ids_round_trip() {
+        for (timestamp, size) in [(0x5f4e3a2b, 0x9a5000), (0x23ed8d63, 0x1450000), (0, 0x1000), (u32::MAX, u64::MAX)] {
+            let file_id = make_file_id(timestamp, size);
+            assert_eq!(parse_file_id(&file_id), Some((timestamp, size)), "{}", file_id);
+        }
+    }
+
+    #[test]
+    fn file_ids_pad_the_timestamp_only() {
+        assert_eq!(make_file_id(0x5f4e3a2b, 0x9a5000), "5F4E3A2B9a5000");
+        assert_eq!(make_file_id(0x1234, 0xab), "00001234ab");
+        assert_eq!(parse_file_id("00001234AB"), Some((0x1234, 0xab)));
+    }
+
+    #[test]
+    fn rejects_what_is_not_a_file_id() {
+        // no size, or not hex
+        assert_eq!(parse_file_id("5F4E3A2B"), None);
+        assert_eq!(parse_file_id("5F4E3A2G9a5000"), None);
+        assert_eq!(parse_file_id(""), None);
+    }
+}
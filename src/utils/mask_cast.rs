@@ -8,38 +8,38 @@ pub trait MaskCast<T> {
 
 impl MaskCast<u64> for u8 {
     fn mask_cast_to(self) -> u64 {
-        return self as u64;
+        self as u64
     }
     fn mask_cast_from(val: u64) -> Self {
-        return val as u8
+        val as u8
     }
 }
 
 impl MaskCast<u64> for u16 {
     fn mask_cast_to(self) -> u64 {
-        return self as u64;
+        self as u64
     }
     fn mask_cast_from(val: u64) -> Self {
-        return val as u16;
+        val as u16
     }
 }
 
 impl MaskCast<u64> for u32 {
     fn mask_cast_to(self) -> u64 {
-        return self as u64;
+        self as u64
     }
 
     fn mask_cast_from(val: u64) -> Self {
-        return val as u32;
+        val as u32
     }
 }
 
 impl MaskCast<u64> for u64 {
     fn mask_cast_from(val: u64) -> Self {
-        return val;
+        val
     }
     fn mask_cast_to(self) -> u64 {
-        return self
+        self
     }
 }
 
@@ -47,6 +47,6 @@ pub fn get_bit_mask_handler(pos: u64, len: u64)-> Box<dyn Fn(u64) -> u64> {
     // Generate a function to get "len" bit, starting at posistion "pos" of a number
     Box::new(move |val: u64| -> u64 {
         // Work-around rust's overflow check when compile
-        (val >> pos) & (((1 as u128) << len) - 1) as u64
+        (val >> pos) & ((1u128 << len) - 1) as u64
     })
 }
@@ -1 +1,7 @@
+pub mod date;
+pub mod file_id;
 pub mod mask_cast;
+pub mod rate_limit;
+pub mod version;
+
+pub use file_id::{make_file_id, parse_file_id};
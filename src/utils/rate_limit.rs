@@ -0,0 +1,56 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A token bucket holding a single token, shared by every worker: requests start
+/// at least 1/rate seconds apart whatever the number of workers. Without a rate
+/// it only holds requests back while the server asks us to slow down.
+pub struct RateLimiter {
+    // nanoseconds between two requests, 0 when there is no limit
+    interval: AtomicU64,
+    // when the next request may start
+    next: Mutex<Option<Instant>>,
+}
+
+impl RateLimiter {
+    pub const fn new() -> Self {
+        RateLimiter {
+            interval: AtomicU64::new(0),
+            next: Mutex::new(None),
+        }
+    }
+
+    pub fn set_rate(&self, per_second: f64) {
+        let interval = Duration::from_secs_f64(1.0 / per_second);
+        self.interval.store(interval.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    /// Block until the next request may start.
+    pub fn acquire(&self) {
+        let interval = Duration::from_nanos(self.interval.load(Ordering::Relaxed));
+        let now = Instant::now();
+        let start = {
+            let mut next = self.next.lock().unwrap_or_else(PoisonError::into_inner);
+            let start = next.map_or(now, |next| next.max(now));
+            *next = Some(start + interval);
+            start
+        };
+        if start > now {
+            thread::sleep(start - now);
+        }
+    }
+
+    /// Hold every request back for `delay`, e.g. after a 429.
+    pub fn pause(&self, delay: Duration) {
+        let until = Instant::now() + delay;
+        let mut next = self.next.lock().unwrap_or_else(PoisonError::into_inner);
+        *next = Some(next.map_or(until, |next| next.max(until)));
+    }
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
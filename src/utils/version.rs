@@ -0,0 +1,53 @@
+use std::fmt;
+
+/// A Windows file version, e.g. 10.0.19041.1234
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Version4 {
+    pub major: u32,
+    pub minor: u32,
+    pub build: u32,
+    pub revision: u32,
+}
+
+impl fmt::Display for Version4 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}.{}.{}", self.major, self.minor, self.build, self.revision)
+    }
+}
+
+/// Parse a version the way winbindex records it, "10.0.19041.1234 (WinBuild.160101.0800)".
+/// Only the part before the first space is used. It must have exactly four numeric
+/// parts, so "10.0" or "10.0.19041.1234.5" are rejected.
+pub fn parse_version(version: &str) -> Option<Version4> {
+    let mut parts = version.split_whitespace().next()?.split('.').map(|part| part.parse::<u32>().ok());
+    let version = Version4 {
+        major: parts.next()??,
+        minor: parts.next()??,
+        build: parts.next()??,
+        revision: parts.next()??,
+    };
+    match parts.next() {
+        Some(_) => None,
+        None => Some(version),
+    }
+}
+
+/// Parse a version given as a bound on the command line, "10.0.22000" or "10.0.22000.1".
+/// Missing trailing parts take `fill`, so a bound of 10.0.22000 can cover every
+/// revision of the build: 0 for a lower bound, u32::MAX for an upper one.
+pub fn parse_version_bound(version: &str, fill: u32) -> Option<Version4> {
+    let parts = version
+        .split('.')
+        .map(|part| part.parse::<u32>().ok())
+        .collect::<Option<Vec<_>>>()?;
+    if parts.is_empty() || parts.len() > 4 {
+        return None;
+    }
+    let part = |index: usize| parts.get(index).copied().unwrap_or(fill);
+    Some(Version4 {
+        major: part(0),
+        minor: part(1),
+        build: part(2),
+        revision: part(3),
+    })
+}
@@ -0,0 +1,510 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, Read};
+use std::ops::ControlFlow;
+use std::path::{Path, PathBuf};
+use std::sync::LazyLock;
+
+use flate2::read::GzDecoder;
+use log::{info, warn};
+use reqwest::header;
+use serde::de::{Deserializer, IgnoredAny, MapAccess, SeqAccess, Visitor};
+
+use crate::download::{response_bytes, send_with_retries, write_atomically};
+use crate::error;
+use crate::utils::date::parse_date;
+use crate::utils::version::{parse_version, Version4};
+
+// 2000-01-01, no PE winbindex lists was built before
+const EARLIEST_BUILD_TIME: u64 = 946684800;
+
+const WINBINDEX_URL: &str = "https://winbindex.m417z.com/data/by_filename_compressed";
+
+// What a PE name derived from a dump name has to end with
+const PE_EXTENSIONS: [&str; 3] = ["exe", "sys", "dll"];
+
+/// The PE a dump lists, from its name. winbindex names its dumps <pe name>.json,
+/// or <pe name>.json.gz when compressed. None when the name is not of a PE, e.g.
+/// test.json, then --pe-name is needed.
+pub fn pe_name_from_dump(dump: &Path) -> Option<String> {
+    let file_name = dump.file_name()?.to_str()?;
+    let file_name = file_name.strip_suffix(".gz").unwrap_or(file_name);
+    let pe_name = file_name.strip_suffix(".json")?;
+    let (_, extension) = pe_name.rsplit_once('.')?;
+    PE_EXTENSIONS
+        .iter()
+        .any(|pe_extension| extension.eq_ignore_ascii_case(pe_extension))
+        .then(|| pe_name.to_string())
+}
+
+/// Open a winbindex dump, gzip compressed dumps are decompressed while reading.
+pub fn open_dump(path: &Path) -> io::Result<Box<dyn Read + Send>> {
+    let file = BufReader::new(File::open(path)?);
+    if path.extension().is_some_and(|ext| ext == "gz") {
+        Ok(Box::new(BufReader::new(GzDecoder::new(file))))
+    } else {
+        Ok(Box::new(file))
+    }
+}
+
+fn is_sha256(key: &str) -> bool {
+    key.len() == 64 && key.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Read a winbindex dump one record at a time instead of loading the whole map,
+/// so memory stays flat whatever the dump size. Returning ControlFlow::Break from
+/// `f` skips the remaining records. A record that does not fit RecordData is
+/// logged and skipped, only broken JSON fails the whole dump.
+///
+/// Besides the by_filename map keyed by SHA256 this reads a map keyed by anything
+/// else (e.g. by version), an array of records and a lone record as in by_sha256.
+/// Records not keyed by their hash need fileInfo.sha256.
+pub fn for_each_record<R, F>(reader: R, f: F) -> serde_json::Result<()>
+where
+    R: Read,
+    F: FnMut(String, RecordData) -> ControlFlow<()>,
+{
+    struct RecordVisitor<F>(F);
+
+    impl<F> RecordVisitor<F>
+    where
+        F: FnMut(String, RecordData) -> ControlFlow<()>,
+    {
+        fn record(&mut self, key: Option<String>, value: serde_json::Value) -> ControlFlow<()> {
+            let sha256_hash = match key {
+                Some(key) if is_sha256(&key) => key,
+                key => match value["fileInfo"]["sha256"].as_str().or(value["sha256"].as_str()) {
+                    Some(sha256_hash) => sha256_hash.to_string(),
+                    None => {
+                        warn!("Skipping record {}, it has no SHA256", key.unwrap_or_default());
+                        return ControlFlow::Continue(());
+                    }
+                },
+            };
+            match serde_json::from_value::<RecordData>(value) {
+                Ok(record) => (self.0)(sha256_hash, record),
+                Err(e) => {
+                    warn!("Skipping malformed record {}: {}", sha256_hash, e);
+                    ControlFlow::Continue(())
+                }
+            }
+        }
+    }
+
+    impl<'de, F> Visitor<'de> for RecordVisitor<F>
+    where
+        F: FnMut(String, RecordData) -> ControlFlow<()>,
+    {
+        type Value = ();
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("a map or an array of winbindex records")
+        }
+
+        fn visit_map<A: MapAccess<'de>>(mut self, mut map: A) -> Result<(), A::Error> {
+            // a lone record, as in the by_sha256 files, starts with its own fields
+            let mut lone_record = serde_json::Map::new();
+            while let Some(key) = map.next_key::<String>()? {
+                let value = map.next_value::<serde_json::Value>()?;
+                if !lone_record.is_empty() || key == "fileInfo" || key == "windowsVersions" {
+                    lone_record.insert(key, value);
+                    continue;
+                }
+                if self.record(Some(key), value).is_break() {
+                    // the rest still has to be read for the map to be well formed
+                    while map.next_entry::<IgnoredAny, IgnoredAny>()?.is_some() {}
+                }
+            }
+            if !lone_record.is_empty() {
+                let _ = self.record(None, lone_record.into());
+            }
+            Ok(())
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(mut self, mut seq: A) -> Result<(), A::Error> {
+            while let Some(value) = seq.next_element::<serde_json::Value>()? {
+                if self.record(None, value).is_break() {
+                    while seq.next_element::<IgnoredAny>()?.is_some() {}
+                }
+            }
+            Ok(())
+        }
+    }
+
+    let mut deserializer = serde_json::Deserializer::from_reader(reader);
+    deserializer.deserialize_any(RecordVisitor(f))?;
+    deserializer.end()
+}
+
+/// Download <pe_name>.json.gz from winbindex into cache_dir and return its path.
+/// A cached copy is revalidated with its ETag/Last-Modified, so an unchanged dump
+/// is not downloaded again.
+pub fn fetch_dump(
+    client: &reqwest::blocking::Client,
+    pe_name: &str,
+    cache_dir: &Path,
+    retries: u32,
+) -> error::Result<PathBuf> {
+    let url = format!("{}/{}.json.gz", WINBINDEX_URL, pe_name);
+    let cache_path = cache_dir.join(format!("{}.json.gz", pe_name));
+    let validators_path = cache_dir.join(format!("{}.json.gz.cache", pe_name));
+    info!("Fetching winbindex dump: {}", url);
+
+    let mut headers = header::HeaderMap::new();
+    if cache_path.is_file()
+        && let Ok(validators) = fs::read_to_string(&validators_path)
+        && let Ok(validators) = serde_json::from_str::<serde_json::Value>(&validators)
+    {
+        if let Some(etag) = validators["etag"].as_str().and_then(|etag| etag.parse().ok()) {
+            headers.insert(header::IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = validators["last_modified"].as_str().and_then(|date| date.parse().ok()) {
+            headers.insert(header::IF_MODIFIED_SINCE, last_modified);
+        }
+    }
+
+    let response = send_with_retries(client, &url, retries, headers)?;
+    if response.status() == reqwest::StatusCode::NOT_MODIFIED {
+        info!("Cached dump is up to date: {}", cache_path.display());
+        return Ok(cache_path);
+    }
+
+    let validator = |name| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.to_string())
+    };
+    let validators = serde_json::json!({
+        "etag": validator(header::ETAG),
+        "last_modified": validator(header::LAST_MODIFIED),
+    });
+    let data = response_bytes(&url, response)?;
+
+    // The validators go last, they must never vouch for a dump that was not fully written
+    fs::create_dir_all(cache_dir)?;
+    match fs::remove_file(&validators_path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+        _ => (),
+    }
+    write_atomically(&cache_path, &data)?;
+    write_atomically(&validators_path, validators.to_string().as_bytes())?;
+    info!("Saved dump to: {}", cache_path.display());
+    Ok(cache_path)
+}
+
+fn default_version() -> String {
+    "".into()
+}
+
+fn default_file_info() -> FileInfo {
+    FileInfo{
+        size: 0,
+        virtual_size: 0,
+        timestamp: 0,
+        version: "".into(),
+        extra: Default::default(),
+    }
+}
+
+// Fields we do not model are kept in `extra` so records serialize back unchanged.
+type Extra = serde_json::Map<String, serde_json::Value>;
+
+// --- 1. Define the necessary data structures for deserialization ---
+
+// The deepest nested structure we need.
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FileInfo {
+    pub size: u64,
+    pub virtual_size: u64,
+    // the PE TimeDateStamp, a record with one that does not fit is malformed
+    pub timestamp: u32,
+    #[serde(default = "default_version")]
+    pub version: String, // e.g., "10.0.10240.17914 (th1.180627-1911)"
+    #[serde(flatten)]
+    pub extra: Extra,
+}
+
+// The structure holding FileInfo and the deeply nested Windows version information.
+// We only need the outermost map key (the SHA256 hash) and the internal data.
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RecordData {
+    #[serde(default = "default_file_info")]
+    pub file_info: FileInfo,
+    #[serde(default)]
+    pub windows_versions: HashMap<String, HashMap<String, Kbs>>,
+    #[serde(flatten)]
+    pub extra: Extra,
+}
+
+impl RecordData {
+    /// The four part file version, None when the record's version is malformed.
+    pub fn file_version(&self) -> Option<Version4> {
+        parse_version(&self.file_info.version)
+    }
+
+    /// When the file was built as seconds since the Unix epoch, None when the record
+    /// cannot tell. This is the PE TimeDateStamp, but on reproducible builds, most
+    /// of Windows 10 and later, that is a hash of the build rather than a time. A
+    /// stamp before 2000 or after `now` cannot be a build time, the release date of
+    /// the oldest update that shipped the file is used instead. A hash that happens
+    /// to fall in between is still taken for a time.
+    pub fn build_time(&self, now: u64) -> Option<u64> {
+        let timestamp = u64::from(self.file_info.timestamp);
+        if (EARLIEST_BUILD_TIME..=now).contains(&timestamp) {
+            return Some(timestamp);
+        }
+        self.updates()
+            .iter()
+            .find_map(|update| parse_date(update.release_date.as_deref()?))
+    }
+
+    /// The updates that shipped this file, oldest first, so the first one is
+    /// where its offsets appeared.
+    pub fn updates(&self) -> Vec<Update> {
+        let mut updates: Vec<_> = self
+            .windows_versions
+            .iter()
+            .flat_map(|(windows_version, kbs)| {
+                kbs.iter().map(move |(kb, kbs)| {
+                    let info = kbs.update_info.as_ref().or(kbs.windows_version_info.as_ref());
+                    Update{
+                        windows_version: windows_version.clone(),
+                        kb: kb.clone(),
+                        release_date: info.and_then(|info| info.release_date.clone()),
+                        release_version: info.and_then(|info| info.release_version.clone()),
+                        url: info.and_then(|info| info.update_url.clone()),
+                    }
+                })
+            })
+            .collect();
+        // records without a date sort last
+        updates.sort_by(|a, b| {
+            (a.release_date.is_none(), &a.release_date, &a.kb, &a.windows_version)
+                .cmp(&(b.release_date.is_none(), &b.release_date, &b.kb, &b.windows_version))
+        });
+        updates
+    }
+}
+
+/// One update a file shipped in, written to offsets.json as an entry of "updates".
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct Update {
+    pub windows_version: String, // e.g., "11-24H2"
+    pub kb: String,              // e.g., "KB5065789", or "BASE" for the release ISO
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub release_date: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub release_version: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+}
+
+// Struct for the KB update, contains the assembly information.
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Kbs {
+    #[serde(default)]
+    pub assemblies: HashMap<String, Assembly>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub update_info: Option<UpdateInfo>,
+    // BASE entries, the file as on the release ISO, have this instead of updateInfo
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub windows_version_info: Option<UpdateInfo>,
+    #[serde(flatten)]
+    pub extra: Extra,
+}
+
+// Struct for the release of the update, every field may be missing.
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UpdateInfo {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub release_date: Option<String>, // e.g., "2025-09-29"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub release_version: Option<String>, // e.g., "26100.6725"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub update_url: Option<String>,
+    // heading, otherWindowsVersions, isoSha256 and anything else
+    #[serde(flatten)]
+    pub extra: Extra,
+}
+
+// Struct for the Assembly information.
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Assembly {
+    pub attributes: Vec<Attribute>,
+    #[serde(flatten)]
+    pub extra: Extra,
+}
+
+// Struct for the Attribute, which contains the filename we need.
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Attribute {
+    #[serde(rename = "sourceName")]
+    pub source_name: String, // This is the PE filename, e.g., "ntkrnlmp.exe"
+    #[serde(rename = "name")]
+    pub destination_name: String, // This is the target filename, e.g., "ntoskrnl.exe"
+    #[serde(flatten)]
+    pub extra: Extra,
+}
+
+// Windows release of every 10.0.<build> we know about
+static OS_VERSIONS: LazyLock<HashMap<&'static str, &'static str>> = LazyLock::new(|| {
+    HashMap::from([
+        ("10.0.26100", "Windows 11 24H2"),
+        ("10.0.22631", "Windows 11 23H2"),
+        ("10.0.22621", "Windows 11 22H2"),
+        ("10.0.22000", "Windows 11 21H2"),
+        ("10.0.19045", "Windows 10 22H2"),
+        ("10.0.19044", "Windows 10 21H2"),
+        ("10.0.19043", "Windows 10 21H1"),
+        ("10.0.19042", "Windows 10 20H2"),
+        ("10.0.19041", "Windows 10 2004"),
+        ("10.0.18363", "Windows 10 1909"),
+        ("10.0.18362", "Windows 10 1903"),
+        ("10.0.17763", "Windows 10 1809"),
+        ("10.0.17134", "Windows 10 1803"),
+        ("10.0.16299", "Windows 10 1709"),
+        ("10.0.15063", "Windows 10 1703"),
+        ("10.0.14393", "Windows 10 1607"),
+        ("10.0.10586", "Windows 10 1511"),
+        ("10.0.10240", "Windows 10 1507"),
+    ])
+});
+
+// The first Windows 11 build, 21H2. Server 2025 shares 26100 with 24H2
+const WINDOWS_11_BUILD: u32 = 22000;
+
+/// The Windows release of a record. Versions order by their numeric file version,
+/// 10.0.19045.3803 < 10.0.22000.194 < 10.0.26100.1, not by the strings.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Version{
+    pub codename: String,
+    // the release, major.minor.build, e.g. 10.0.19045
+    pub version: String,
+    // the whole version of the file, e.g. 10.0.19045.3803
+    pub file_version: Version4,
+}
+
+impl Version {
+    /// Whether this is a named release rather than an Insider or otherwise unknown build.
+    pub fn is_known(&self) -> bool {
+        OS_VERSIONS.contains_key(self.version.as_str())
+    }
+
+    /// The build number, e.g. 19045 for Windows 10 22H2.
+    pub fn build_number(&self) -> u32 {
+        self.file_version.build
+    }
+
+    /// Whether the build is Windows 11 or later, Insider builds included.
+    pub fn is_windows_11(&self) -> bool {
+        (self.file_version.major, self.file_version.minor) == (10, 0) && self.build_number() >= WINDOWS_11_BUILD
+    }
+}
+
+// codename and version follow from file_version, it alone decides the order
+impl Ord for Version {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.file_version.cmp(&other.file_version)
+    }
+}
+
+impl PartialOrd for Version {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+pub fn get_os_version(record: &RecordData) -> Option<Version> {
+    // 10.0.19041.1234 (WinBuild.160101.0800) -> 10.0.19041
+    let version = record.file_version()?;
+    let build = format!("{}.{}.{}", version.major, version.minor, version.build);
+    let codename = match OS_VERSIONS.get(build.as_str()) {
+        Some(&codename) => codename.to_string(),
+        // Insider and other builds we have no name for yet are still processed
+        None if (version.major, version.minor) == (10, 0) => format!("Windows (build {})", version.build),
+        None => return None,
+    };
+    Some(Version{
+        codename,
+        version: build,
+        file_version: version,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timestamps_are_32_bits() {
+        let hash = "4f4e0772425290d60c20b8d32800fb41ffb1a6a6b1edf710c27bf487ada50f52";
+        let dump = |timestamp: u64| {
+            format!(r#"{{"{}": {{"fileInfo": {{"size": 1, "virtualSize": 1, "timestamp": {}}}}}}}"#, hash, timestamp)
+        };
+        let mut timestamps = Vec::new();
+        for timestamp in [0x23ed8d63, 1 << 32] {
+            for_each_record(dump(timestamp).as_bytes(), |_, record| {
+                timestamps.push(record.file_info.timestamp);
+                ControlFlow::Continue(())
+            })
+            .unwrap();
+        }
+        // the one that does not fit is skipped as malformed
+        assert_eq!(timestamps, [0x23ed8d63]);
+    }
+
+    fn os_version(version: &str) -> Version {
+        let record = serde_json::json!({"fileInfo": {"size": 1, "virtualSize": 1, "timestamp": 0, "version": version}});
+        get_os_version(&serde_json::from_value(record).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn versions_order_by_build_then_revision() {
+        let releases = ["10.0.19045.3803", "10.0.22000.2652", "10.0.22631.2861", "10.0.26100.6725"].map(os_version);
+        assert!(releases.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(releases.iter().max().unwrap().codename, "Windows 11 24H2");
+
+        // within a release the revision decides, the codename plays no part
+        assert!(os_version("10.0.22631.1") < os_version("10.0.22631.2861"));
+        assert_eq!(os_version("10.0.19045.3803").cmp(&os_version("10.0.19045.3803")), std::cmp::Ordering::Equal);
+        assert!(os_version("10.0.26100.1") < os_version("10.0.26200.1"));
+    }
+
+    #[test]
+    fn windows_11_starts_at_build_22000() {
+        assert!(!os_version("10.0.19045.3803").is_windows_11());
+        // the last Insider builds before the first Windows 11 release
+        assert!(!os_version("10.0.21996.1").is_windows_11());
+        assert!(os_version("10.0.22000.1").is_windows_11());
+        assert!(os_version("10.0.22631.2861").is_windows_11());
+        // unnamed Insider builds count too
+        let insider = os_version("10.0.27881.1000");
+        assert!(!insider.is_known());
+        assert!(insider.is_windows_11());
+        assert_eq!(insider.build_number(), 27881);
+    }
+
+    #[test]
+    fn pe_name_from_dump_names() {
+        let pe_name = |dump: &str| pe_name_from_dump(Path::new(dump));
+        assert_eq!(pe_name("ntoskrnl.exe.json").as_deref(), Some("ntoskrnl.exe"));
+        assert_eq!(pe_name("dumps/win32k.sys.json").as_deref(), Some("win32k.sys"));
+        assert_eq!(pe_name("CI.DLL.json").as_deref(), Some("CI.DLL"));
+        assert_eq!(pe_name("test.json"), None);
+        assert_eq!(pe_name("offsets.json"), None);
+        assert_eq!(pe_name("ntoskrnl.exe.json.gz").as_deref(), Some("ntoskrnl.exe"));
+        assert_eq!(pe_name("test.json.gz"), None);
+        assert_eq!(pe_name("ntoskrnl.exe"), None);
+    }
+}
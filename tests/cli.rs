@@ -0,0 +1,36 @@
+//! The lpus binary as a user runs it, for what only shows through the command line
+//! and the environment.
+
+use std::process::{Command, Output};
+
+const TEST_DUMP: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/test.json");
+
+fn lpus(args: &[&str], env: &[(&str, &str)]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_lpus"))
+        .args(args)
+        .envs(env.iter().copied())
+        .env_remove("_NT_SYMBOL_PATH")
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn switches_take_boolish_values_from_the_environment() {
+    let url = "https://msdl.microsoft.com/download/symbols/ntoskrnl.exe/23ED8D631450000/ntoskrnl.exe";
+    for value in ["1", "true", "yes", "on"] {
+        let output = lpus(&[TEST_DUMP, "--pe-name", "ntoskrnl.exe"], &[("KOFF_DRY_RUN", value)]);
+        assert!(output.status.success(), "KOFF_DRY_RUN={}: {}", value, String::from_utf8_lossy(&output.stderr));
+        assert!(String::from_utf8_lossy(&output.stdout).starts_with(url), "KOFF_DRY_RUN={}", value);
+    }
+
+    let output = lpus(&[TEST_DUMP, "--pe-name", "ntoskrnl.exe"], &[("KOFF_DRY_RUN", "maybe")]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("--dry-run"));
+}
+
+#[test]
+fn list_versions_reads_its_input_from_the_environment() {
+    let output = lpus(&["list-versions"], &[("KOFF_INPUT", TEST_DUMP)]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(String::from_utf8_lossy(&output.stdout).contains("10.0.26100.6725"));
+}
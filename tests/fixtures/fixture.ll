@@ -0,0 +1,118 @@
+; Source of tests/fixtures/fixture.exe and fixture.pdb, a tiny x64 "kernel" whose
+; pdb has a few structs shaped like the real ones. Rebuild with
+;   llc -filetype=obj fixture.ll -o fixture.obj
+;   rust-lld -flavor link /DEBUG /NODEFAULTLIB /ENTRY:KiSystemStartup /SUBSYSTEM:NATIVE
+;       /OUT:fixture.exe /PDB:fixture.pdb /PDBALTPATH:fixture.pdb fixture.obj
+target datalayout = "e-m:w-p270:32:32-p271:32:32-p272:64:64-i64:64-f80:128-n8:16:32:64-S128"
+target triple = "x86_64-pc-windows-msvc"
+
+@SystemProcess = dso_local global [112 x i8] zeroinitializer, align 16, !dbg !50
+@PsInitialSystemProcess = dso_local global i8* getelementptr ([112 x i8], [112 x i8]* @SystemProcess, i64 0, i64 0), align 8, !dbg !52
+@PsLoadedModuleList = dso_local global [16 x i8] zeroinitializer, align 8, !dbg !54
+
+define dso_local i32 @KiSystemStartup() !dbg !60 {
+  %1 = load i8*, i8** @PsInitialSystemProcess, align 8
+  %2 = icmp eq i8* %1, null
+  br i1 %2, label %3, label %4
+3:
+  ret i32 1
+4:
+  ret i32 0
+}
+
+!llvm.dbg.cu = !{!0}
+!llvm.module.flags = !{!100, !101}
+!100 = !{i32 2, !"CodeView", i32 1}
+!101 = !{i32 2, !"Debug Info Version", i32 3}
+
+!0 = distinct !DICompileUnit(language: DW_LANG_C99, file: !1, producer: "fixture", isOptimized: false, runtimeVersion: 0, emissionKind: FullDebug, enums: !{!40}, globals: !{!50, !52, !54})
+!1 = !DIFile(filename: "fixture.c", directory: "C:\\fixture")
+
+!2 = !DIBasicType(name: "unsigned long", size: 32, encoding: DW_ATE_unsigned)
+!3 = !DIBasicType(name: "long", size: 32, encoding: DW_ATE_signed)
+!4 = !DIBasicType(name: "unsigned long long", size: 64, encoding: DW_ATE_unsigned)
+!5 = !DIBasicType(name: "unsigned char", size: 8, encoding: DW_ATE_unsigned_char)
+!6 = !DIBasicType(name: "int", size: 32, encoding: DW_ATE_signed)
+!7 = !DIDerivedType(tag: DW_TAG_pointer_type, baseType: null, size: 64)
+
+; struct _LIST_ENTRY { struct _LIST_ENTRY *Flink, *Blink; }
+!10 = distinct !DICompositeType(tag: DW_TAG_structure_type, name: "_LIST_ENTRY", file: !1, size: 128, elements: !11, identifier: "._LIST_ENTRY")
+!11 = !{!12, !13}
+!12 = !DIDerivedType(tag: DW_TAG_member, name: "Flink", scope: !10, file: !1, baseType: !14, size: 64, offset: 0)
+!13 = !DIDerivedType(tag: DW_TAG_member, name: "Blink", scope: !10, file: !1, baseType: !14, size: 64, offset: 64)
+!14 = !DIDerivedType(tag: DW_TAG_pointer_type, baseType: !10, size: 64)
+
+; struct _DISPATCHER_HEADER { ULONG Lock; LONG SignalState; LIST_ENTRY WaitListHead; }
+!15 = distinct !DICompositeType(tag: DW_TAG_structure_type, name: "_DISPATCHER_HEADER", file: !1, size: 192, elements: !16, identifier: "._DISPATCHER_HEADER")
+!16 = !{!17, !18, !19}
+!17 = !DIDerivedType(tag: DW_TAG_member, name: "Lock", scope: !15, file: !1, baseType: !2, size: 32, offset: 0)
+!18 = !DIDerivedType(tag: DW_TAG_member, name: "SignalState", scope: !15, file: !1, baseType: !3, size: 32, offset: 32)
+!19 = !DIDerivedType(tag: DW_TAG_member, name: "WaitListHead", scope: !15, file: !1, baseType: !10, size: 128, offset: 64)
+
+; struct _KPROCESS { DISPATCHER_HEADER Header; LIST_ENTRY ThreadListHead; ULONGLONG DirectoryTableBase; }
+!20 = distinct !DICompositeType(tag: DW_TAG_structure_type, name: "_KPROCESS", file: !1, size: 384, elements: !21, identifier: "._KPROCESS")
+!21 = !{!22, !23, !24}
+!22 = !DIDerivedType(tag: DW_TAG_member, name: "Header", scope: !20, file: !1, baseType: !15, size: 192, offset: 0)
+!23 = !DIDerivedType(tag: DW_TAG_member, name: "ThreadListHead", scope: !20, file: !1, baseType: !10, size: 128, offset: 192)
+!24 = !DIDerivedType(tag: DW_TAG_member, name: "DirectoryTableBase", scope: !20, file: !1, baseType: !4, size: 64, offset: 320)
+
+; union _EX_FAST_REF { PVOID Object; ULONGLONG RefCnt : 4; ULONGLONG Value; }
+!25 = distinct !DICompositeType(tag: DW_TAG_union_type, name: "_EX_FAST_REF", file: !1, size: 64, elements: !26, identifier: "._EX_FAST_REF")
+!26 = !{!27, !28, !29}
+!27 = !DIDerivedType(tag: DW_TAG_member, name: "Object", scope: !25, file: !1, baseType: !7, size: 64, offset: 0)
+!28 = !DIDerivedType(tag: DW_TAG_member, name: "RefCnt", scope: !25, file: !1, baseType: !4, size: 4, offset: 0, flags: DIFlagBitField, extraData: i64 0)
+!29 = !DIDerivedType(tag: DW_TAG_member, name: "Value", scope: !25, file: !1, baseType: !4, size: 64, offset: 0)
+
+; struct _EPROCESS {
+;     KPROCESS Pcb;                          // 0x0
+;     PVOID UniqueProcessId;                 // 0x30
+;     LIST_ENTRY ActiveProcessLinks;         // 0x38
+;     union {                                // 0x48
+;         ULONG Flags;
+;         struct {
+;             ULONG CreateReported : 1;
+;             ULONG NoDebugInherit : 1;
+;             ULONG ProcessExiting : 1;
+;             ULONG ExceptionPortState : 3;
+;         };
+;     };
+;     EX_FAST_REF Token;                     // 0x50
+;     POOL_TYPE PoolType;                    // 0x58
+;     UCHAR ImageFileName[15];               // 0x5c
+; };
+!30 = distinct !DICompositeType(tag: DW_TAG_structure_type, name: "_EPROCESS", file: !1, size: 896, elements: !31, identifier: "._EPROCESS")
+!31 = !{!32, !33, !34, !35, !45, !46, !47}
+!32 = !DIDerivedType(tag: DW_TAG_member, name: "Pcb", scope: !30, file: !1, baseType: !20, size: 384, offset: 0)
+!33 = !DIDerivedType(tag: DW_TAG_member, name: "UniqueProcessId", scope: !30, file: !1, baseType: !7, size: 64, offset: 384)
+!34 = !DIDerivedType(tag: DW_TAG_member, name: "ActiveProcessLinks", scope: !30, file: !1, baseType: !10, size: 128, offset: 448)
+!35 = !DIDerivedType(tag: DW_TAG_member, scope: !30, file: !1, baseType: !36, size: 32, offset: 576)
+!36 = distinct !DICompositeType(tag: DW_TAG_union_type, scope: !30, file: !1, size: 32, elements: !37)
+!37 = !{!38, !39}
+!38 = !DIDerivedType(tag: DW_TAG_member, name: "Flags", scope: !36, file: !1, baseType: !2, size: 32, offset: 0)
+!39 = !DIDerivedType(tag: DW_TAG_member, scope: !36, file: !1, baseType: !41, size: 32, offset: 0)
+!41 = distinct !DICompositeType(tag: DW_TAG_structure_type, scope: !36, file: !1, size: 32, elements: !42)
+!42 = !{!43, !44, !48, !49}
+!43 = !DIDerivedType(tag: DW_TAG_member, name: "CreateReported", scope: !41, file: !1, baseType: !2, size: 1, offset: 0, flags: DIFlagBitField, extraData: i64 0)
+!44 = !DIDerivedType(tag: DW_TAG_member, name: "NoDebugInherit", scope: !41, file: !1, baseType: !2, size: 1, offset: 1, flags: DIFlagBitField, extraData: i64 0)
+!48 = !DIDerivedType(tag: DW_TAG_member, name: "ProcessExiting", scope: !41, file: !1, baseType: !2, size: 1, offset: 2, flags: DIFlagBitField, extraData: i64 0)
+!49 = !DIDerivedType(tag: DW_TAG_member, name: "ExceptionPortState", scope: !41, file: !1, baseType: !2, size: 3, offset: 3, flags: DIFlagBitField, extraData: i64 0)
+!45 = !DIDerivedType(tag: DW_TAG_member, name: "Token", scope: !30, file: !1, baseType: !25, size: 64, offset: 640)
+!46 = !DIDerivedType(tag: DW_TAG_member, name: "PoolType", scope: !30, file: !1, baseType: !40, size: 32, offset: 704)
+!47 = !DIDerivedType(tag: DW_TAG_member, name: "ImageFileName", scope: !30, file: !1, baseType: !56, size: 120, offset: 736)
+!56 = !DICompositeType(tag: DW_TAG_array_type, baseType: !5, size: 120, elements: !{!57})
+!57 = !DISubrange(count: 15)
+
+; enum _POOL_TYPE { NonPagedPool, PagedPool, NonPagedPoolNx = 512 }
+!40 = distinct !DICompositeType(tag: DW_TAG_enumeration_type, name: "_POOL_TYPE", file: !1, baseType: !6, size: 32, elements: !58, identifier: "._POOL_TYPE")
+!58 = !{!DIEnumerator(name: "NonPagedPool", value: 0), !DIEnumerator(name: "PagedPool", value: 1), !DIEnumerator(name: "NonPagedPoolNx", value: 512)}
+
+!50 = !DIGlobalVariableExpression(var: !51, expr: !DIExpression())
+!51 = distinct !DIGlobalVariable(name: "SystemProcess", scope: !0, file: !1, line: 1, type: !30, isLocal: false, isDefinition: true)
+!52 = !DIGlobalVariableExpression(var: !53, expr: !DIExpression())
+!53 = distinct !DIGlobalVariable(name: "PsInitialSystemProcess", scope: !0, file: !1, line: 2, type: !59, isLocal: false, isDefinition: true)
+!59 = !DIDerivedType(tag: DW_TAG_pointer_type, baseType: !30, size: 64)
+!54 = !DIGlobalVariableExpression(var: !55, expr: !DIExpression())
+!55 = distinct !DIGlobalVariable(name: "PsLoadedModuleList", scope: !0, file: !1, line: 3, type: !10, isLocal: false, isDefinition: true)
+
+!60 = distinct !DISubprogram(name: "KiSystemStartup", scope: !1, file: !1, line: 4, type: !61, scopeLine: 4, spFlags: DISPFlagDefinition, unit: !0)
+!61 = !DISubroutineType(types: !{!6})
@@ -0,0 +1,42 @@
+; Source of tests/fixtures/win32k.exe and win32k.pdb, a tiny x64 image whose
+; pdb has C++ classes shaped like win32k's. Rebuild with
+;   llc -filetype=obj win32k.ll -o win32k.obj
+;   rust-lld -flavor link /DEBUG /NODEFAULTLIB /ENTRY:DriverEntry /SUBSYSTEM:NATIVE
+;       /OUT:win32k.exe /PDB:win32k.pdb /PDBALTPATH:win32k.pdb win32k.obj
+target datalayout = "e-m:w-p270:32:32-p271:32:32-p272:64:64-i64:64-f80:128-n8:16:32:64-S128"
+target triple = "x86_64-pc-windows-msvc"
+
+define dso_local i32 @DriverEntry() !dbg !60 {
+  ret i32 0
+}
+
+!llvm.dbg.cu = !{!0}
+!llvm.module.flags = !{!100, !101}
+!100 = !{i32 2, !"CodeView", i32 1}
+!101 = !{i32 2, !"Debug Info Version", i32 3}
+
+!0 = distinct !DICompileUnit(language: DW_LANG_C_plus_plus, file: !1, producer: "fixture", isOptimized: false, runtimeVersion: 0, emissionKind: FullDebug, retainedTypes: !{!74})
+!1 = !DIFile(filename: "win32k.cpp", directory: "C:\\fixture")
+
+!2 = !DIBasicType(name: "unsigned long", size: 32, encoding: DW_ATE_unsigned)
+!6 = !DIBasicType(name: "int", size: 32, encoding: DW_ATE_signed)
+!7 = !DIDerivedType(tag: DW_TAG_pointer_type, baseType: null, size: 64)
+
+; struct _THRDESKHEAD { HANDLE h; ULONG cLockObj; PVOID pti; }
+!70 = distinct !DICompositeType(tag: DW_TAG_structure_type, name: "_THRDESKHEAD", file: !1, size: 192, elements: !71, identifier: "._THRDESKHEAD")
+!71 = !{!72, !73, !78}
+!72 = !DIDerivedType(tag: DW_TAG_member, name: "h", scope: !70, file: !1, baseType: !7, size: 64, offset: 0)
+!73 = !DIDerivedType(tag: DW_TAG_member, name: "cLockObj", scope: !70, file: !1, baseType: !2, size: 32, offset: 64)
+!78 = !DIDerivedType(tag: DW_TAG_member, name: "pti", scope: !70, file: !1, baseType: !7, size: 64, offset: 128)
+
+; class tagWND : public _THRDESKHEAD { ULONG state; ULONG state2; tagWND *spwndNext; }
+!74 = distinct !DICompositeType(tag: DW_TAG_class_type, name: "tagWND", file: !1, size: 320, elements: !75, identifier: ".?AVtagWND@@")
+!75 = !{!76, !77, !79, !80}
+!76 = !DIDerivedType(tag: DW_TAG_inheritance, scope: !74, baseType: !70, offset: 0, flags: DIFlagPublic, extraData: i32 0)
+!77 = !DIDerivedType(tag: DW_TAG_member, name: "state", scope: !74, file: !1, baseType: !2, size: 32, offset: 192)
+!79 = !DIDerivedType(tag: DW_TAG_member, name: "state2", scope: !74, file: !1, baseType: !2, size: 32, offset: 224)
+!80 = !DIDerivedType(tag: DW_TAG_member, name: "spwndNext", scope: !74, file: !1, baseType: !81, size: 64, offset: 256)
+!81 = !DIDerivedType(tag: DW_TAG_pointer_type, baseType: !74, size: 64)
+
+!60 = distinct !DISubprogram(name: "DriverEntry", scope: !1, file: !1, line: 1, type: !61, scopeLine: 1, spFlags: DISPFlagDefinition, unit: !0)
+!61 = !DISubroutineType(types: !{!6})